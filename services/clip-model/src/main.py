@@ -41,6 +41,9 @@ class HealthResponse(BaseModel):
 
 class VectorResponse(BaseModel):
     vector: list[float]
+
+class VectorsResponse(BaseModel):
+    vectors: list[list[float]]
     
 @app.get("/api/v1/clip/health", response_model=HealthResponse)
 async def health_check():
@@ -90,5 +93,28 @@ async def embed_text(text: str = Body(..., embed=True)):
     except Exception as e:
         raise HTTPException(status_code=500, detail=str(e))
 
+@app.post("/api/v1/clip/texts-to-vectors", response_model=VectorsResponse)
+async def embed_texts(texts: list[str] = Body(..., embed=True)):
+    try:
+        if not texts:
+            return VectorsResponse(vectors=[])
+
+        # Preprocess all the texts as one batch, texts longer than the context of the model are truncated
+        inputs = processor(text=texts, images=None, return_tensors="pt", padding=True, truncation=True)
+
+        # Generate embeddings
+        with torch.no_grad():
+            outputs = model.get_text_features(**inputs)
+
+        # Normalize embeddings
+        text_embeddings = outputs / outputs.norm(dim=-1, keepdim=True)
+
+        # Convert to list for JSON response, in the order of the texts
+        vectors = text_embeddings.numpy().tolist()
+
+        return VectorsResponse(vectors=vectors)
+    except Exception as e:
+        raise HTTPException(status_code=500, detail=str(e))
+
 if __name__ == "__main__":
     uvicorn.run("main:app", host="0.0.0.0", port=8000, reload=True) 
\ No newline at end of file
@@ -0,0 +1,22 @@
+-- Jobs of `xlib::jobs`, see `xlib/src/jobs/queue.rs` for the queries
+CREATE TABLE job (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL,
+    -- Deduplicates the jobs of a kind, e.g. the image name. NULL for jobs that may repeat.
+    key TEXT NULL,
+    payload JSONB NOT NULL DEFAULT '{}',
+    -- Higher first
+    priority SMALLINT NOT NULL DEFAULT 0,
+    -- queued, running, done or failed
+    status TEXT NOT NULL DEFAULT 'queued',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    max_attempts INTEGER NOT NULL,
+    run_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- A running job not finished by then is claimed again
+    locked_until TIMESTAMPTZ NULL,
+    last_error TEXT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE UNIQUE INDEX job_kind_key_idx ON job (kind, key);
+CREATE INDEX job_claim_idx ON job (priority DESC, run_at) WHERE status IN ('queued', 'running');
@@ -0,0 +1,277 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use confique::Config;
+use xlib::jobs::{RunnerConfig, Schedule};
+
+use crate::{preprocess::PreprocessConfig, safety::SafetyConfig, tagging::TaggingConfig};
+
+// Read next to the binary, `CONFIG_FILE` points to another file
+const DEFAULT_CONFIG_FILE: &str = "config.yaml";
+
+// Configuration of the worker, loaded from `config.yaml`. Environment variables override the
+// values of the file, and every value not in either has a default.
+#[derive(Config)]
+pub struct WorkerConfig {
+    // Serves `/metrics` and the health checks
+    #[config(nested)]
+    pub public_http: HttpConfig,
+    // Control API, keep it off the public network
+    #[config(nested)]
+    pub private_http: PrivateHttpConfig,
+    #[config(nested)]
+    pub database: DatabaseConfig,
+    #[config(nested)]
+    pub jobs: JobsConfig,
+    #[config(nested)]
+    pub images: ImagesConfig,
+    #[config(nested)]
+    pub qdrant: QdrantConfig,
+    #[config(nested)]
+    pub clip: ClipConfig,
+    #[config(nested)]
+    pub preprocess: PreprocessConfig,
+    #[config(nested)]
+    pub tagging: TaggingConfig,
+    #[config(nested)]
+    pub safety: SafetyConfig,
+    #[config(nested)]
+    pub health: HealthConfig,
+    #[config(nested)]
+    pub shutdown: ShutdownConfig,
+}
+
+impl WorkerConfig {
+    pub fn load() -> Result<Self, confique::Error> {
+        let file = std::env::var("CONFIG_FILE").unwrap_or_else(|_| DEFAULT_CONFIG_FILE.to_string());
+        Self::builder().env().file(file).load()
+    }
+}
+
+#[derive(Config)]
+pub struct HttpConfig {
+    #[config(default = "0.0.0.0")]
+    pub addr: Ipv4Addr,
+    #[config(default = 3000)]
+    pub port: u16,
+}
+
+impl HttpConfig {
+    pub const fn socket_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.addr, self.port)
+    }
+}
+
+#[derive(Config)]
+pub struct PrivateHttpConfig {
+    #[config(default = "0.0.0.0")]
+    pub addr: Ipv4Addr,
+    #[config(default = 5000)]
+    pub port: u16,
+}
+
+impl PrivateHttpConfig {
+    pub const fn socket_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.addr, self.port)
+    }
+}
+
+// Holds the job table, see `migrations`
+#[derive(Config)]
+pub struct DatabaseConfig {
+    // required
+    #[config(env = "DATABASE_HOSTNAME")]
+    pub hostname: String,
+    #[config(env = "DATABASE_PORT")]
+    pub port: Option<u16>,
+    #[config(env = "DATABASE_USER")]
+    pub user: Option<String>,
+    #[config(env = "DATABASE_PASSWORD")]
+    pub password: Option<String>,
+    #[config(default = "img-to-vec-worker", env = "DATABASE_NAME")]
+    pub name: String,
+    #[config(default = 2, validate(*max_connections > 0, "must be positive"))]
+    pub max_connections: u32,
+    // How long a query waits for a free connection of the pool
+    #[config(default = 30, validate(*acquire_timeout_secs > 0, "must be positive"))]
+    pub acquire_timeout_secs: u64,
+}
+
+impl DatabaseConfig {
+    pub const fn acquire_timeout(&self) -> Duration {
+        Duration::from_secs(self.acquire_timeout_secs)
+    }
+}
+
+#[derive(Config)]
+pub struct JobsConfig {
+    // How often the job table is polled while the worker is idle
+    #[config(default = 1, validate(*poll_interval_secs > 0, "must be positive"))]
+    pub poll_interval_secs: u64,
+    // A job is taken again by another worker once its worker isn't heard of for that long
+    #[config(default = 120, validate(*visibility_timeout_secs > 1, "must be more than 1"))]
+    pub visibility_timeout_secs: u64,
+    // Attempts of an image before it goes to the dead-letter list
+    #[config(default = 5, validate(*max_attempts > 0, "must be positive"))]
+    pub max_attempts: i32,
+    // Wait before the first retry, doubled at every attempt
+    #[config(default = 10)]
+    pub retry_backoff_secs: u64,
+}
+
+impl JobsConfig {
+    pub const fn runner_config(&self, drain_timeout: Duration) -> RunnerConfig {
+        RunnerConfig {
+            poll_interval: Duration::from_secs(self.poll_interval_secs),
+            visibility_timeout: Duration::from_secs(self.visibility_timeout_secs),
+            retry_backoff: Duration::from_secs(self.retry_backoff_secs),
+            drain_timeout,
+        }
+    }
+}
+
+#[derive(Config)]
+pub struct ImagesConfig {
+    // Scanned recursively, the path relative to it identifies an image
+    #[config(default = "/images", env = "IMAGES_DIR")]
+    pub dir: PathBuf,
+    // Cron expression with seconds: `sec min hour day-of-month month day-of-week [year]`
+    #[config(
+        default = "*/10 * * * * *",
+        env = "IMAGES_SCAN_SCHEDULE",
+        validate = cron_schedule
+    )]
+    pub scan_schedule: String,
+}
+
+impl ImagesConfig {
+    pub fn scan_schedule(&self) -> Schedule {
+        Schedule::from_str(&self.scan_schedule).expect("validated when loaded")
+    }
+}
+
+fn cron_schedule(value: &impl AsRef<str>) -> Result<(), &'static str> {
+    Schedule::from_str(value.as_ref())
+        .map(|_| ())
+        .map_err(|_| "must be a cron expression with seconds")
+}
+
+#[derive(Config)]
+pub struct QdrantConfig {
+    #[config(default = "http://qdrant:6334", env = "QDRANT_URL", validate = not_empty)]
+    pub url: String,
+    // Created with `vector_size` and the cosine distance if it doesn't exist
+    #[config(
+        default = "clip_images_collection",
+        env = "QDRANT_COLLECTION",
+        validate = not_empty
+    )]
+    pub collection: String,
+    // Size of the vectors of the CLIP model, 512 for clip-vit-base-patch32
+    #[config(default = 512, validate(*vector_size > 0, "must be positive"))]
+    pub vector_size: u64,
+    #[config(default = 10, validate(*timeout_secs > 0, "must be positive"))]
+    pub timeout_secs: u64,
+    #[config(default = 5, validate(*connect_timeout_secs > 0, "must be positive"))]
+    pub connect_timeout_secs: u64,
+}
+
+impl QdrantConfig {
+    pub const fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub const fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+}
+
+#[derive(Config)]
+pub struct ClipConfig {
+    #[config(
+        default = "http://clip-model:8000",
+        env = "CLIP_MODEL_URL",
+        validate = not_empty
+    )]
+    pub url: String,
+    // Embedding an image on CPU may take a while
+    #[config(default = 60, validate(*timeout_secs > 0, "must be positive"))]
+    pub timeout_secs: u64,
+    #[config(default = 5, validate(*connect_timeout_secs > 0, "must be positive"))]
+    pub connect_timeout_secs: u64,
+}
+
+impl ClipConfig {
+    pub const fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub const fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+}
+
+#[derive(Config)]
+pub struct HealthConfig {
+    // How long the readiness probe waits for each dependency
+    #[config(default = 2, validate(*probe_timeout_secs > 0, "must be positive"))]
+    pub probe_timeout_secs: u64,
+}
+
+impl HealthConfig {
+    pub const fn probe_timeout(&self) -> Duration {
+        Duration::from_secs(self.probe_timeout_secs)
+    }
+}
+
+#[derive(Config)]
+pub struct ShutdownConfig {
+    // How long the image being processed is given to finish on shutdown, keep it below the grace
+    // period of the container (`stop_grace_period`, 10s by default in Docker). It is handed back
+    // to the job table after it.
+    #[config(default = 8, env = "SHUTDOWN_DRAIN_TIMEOUT_SECS")]
+    pub drain_timeout_secs: u64,
+}
+
+impl ShutdownConfig {
+    pub const fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+}
+
+pub fn not_empty(value: &impl AsRef<str>) -> Result<(), &'static str> {
+    if value.as_ref().is_empty() {
+        return Err("must not be empty");
+    }
+    Ok(())
+}
+
+// Comma separated list for environment variables, blank items are dropped
+pub fn list_by_comma(input: &str) -> Result<Vec<String>, std::convert::Infallible> {
+    Ok(list_by(input, ','))
+}
+
+// Semicolon separated list, for items that may contain commas
+pub fn list_by_semicolon(input: &str) -> Result<Vec<String>, std::convert::Infallible> {
+    Ok(list_by(input, ';'))
+}
+
+fn list_by(input: &str, separator: char) -> Vec<String> {
+    input
+        .split(separator)
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn not_empty_list(value: &impl AsRef<[String]>) -> Result<(), &'static str> {
+    if value.as_ref().is_empty() {
+        return Err("must not be empty");
+    }
+    Ok(())
+}
@@ -0,0 +1,290 @@
+use std::{
+    collections::VecDeque,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use xlib::{
+    app::problem::Problem,
+    jobs::{JobQueue, RunnerHandle},
+};
+
+use crate::{
+    jobs::{EmbedImage, EMBED_IMAGE, REPROCESS_PRIORITY, SCAN_IMAGES},
+    pipeline::{ImageError, Stage},
+};
+
+// Errors kept for the status, the oldest are dropped first
+const LAST_ERRORS_CAPACITY: usize = 20;
+// Most recent first
+const DEAD_LETTER_LIMIT: i64 = 1000;
+
+// State of the worker shared with the control API. The images are queued in the job table, the
+// API changes what the job runner does next.
+pub struct Control {
+    queue: JobQueue,
+    runner: RunnerHandle,
+    max_attempts: i32,
+    state: Mutex<ControlState>,
+}
+
+#[derive(Default)]
+struct ControlState {
+    counts: Counts,
+    last_errors: VecDeque<Failure>,
+}
+
+#[derive(Default, Clone, Copy, Serialize)]
+struct Counts {
+    discovered: u64,
+    embedded: u64,
+    failed: u64,
+}
+
+#[derive(Clone, Serialize)]
+struct Failure {
+    image_name: String,
+    stage: Stage,
+    error: String,
+    at: DateTime<Utc>,
+}
+
+impl Control {
+    pub fn new(queue: JobQueue, runner: RunnerHandle, max_attempts: i32) -> Self {
+        Self {
+            queue,
+            runner,
+            max_attempts,
+            state: Mutex::default(),
+        }
+    }
+
+    pub fn discovered(&self, count: u64) {
+        self.state.lock().unwrap().counts.discovered += count;
+        metrics::counter!("worker_images_discovered_total").increment(count);
+    }
+
+    pub fn complete(&self) {
+        self.state.lock().unwrap().counts.embedded += 1;
+        metrics::counter!("worker_images_embedded_total").increment(1);
+    }
+
+    pub fn fail(&self, image_name: String, error: &ImageError) {
+        let failure = Failure {
+            image_name,
+            stage: error.stage,
+            error: error.message.clone(),
+            at: Utc::now(),
+        };
+        let mut state = self.state.lock().unwrap();
+        state.counts.failed += 1;
+        if state.last_errors.len() == LAST_ERRORS_CAPACITY {
+            state.last_errors.pop_front();
+        }
+        state.last_errors.push_back(failure);
+        drop(state);
+        metrics::counter!("worker_images_failed_total", "stage" => error.stage.as_str())
+            .increment(1);
+    }
+}
+
+#[derive(Clone)]
+struct ControlApiState {
+    control: Arc<Control>,
+    images_dir: PathBuf,
+}
+
+// Served on the private port only
+pub fn router(control: Arc<Control>, images_dir: PathBuf) -> Router {
+    Router::new()
+        .route("/control/v1/status", get(status_handler))
+        .route("/control/v1/pause", post(pause_handler))
+        .route("/control/v1/resume", post(resume_handler))
+        .route("/control/v1/rescan", post(rescan_handler))
+        .route("/control/v1/images/reprocess", post(reprocess_handler))
+        .route("/control/v1/dead-letter", get(dead_letter_handler))
+        .route(
+            "/control/v1/dead-letter/requeue",
+            post(requeue_dead_letter_handler),
+        )
+        .with_state(ControlApiState {
+            control,
+            images_dir,
+        })
+}
+
+// Rendered as RFC 7807 problems, as the errors of the web server
+#[derive(Debug, thiserror::Error)]
+enum ControlError {
+    #[error("invalid request body: {}", .0.body_text())]
+    InvalidBody(#[from] JsonRejection),
+    #[error("{0} is not a path inside the images directory")]
+    InvalidImageName(String),
+    #[error("image {0} does not exist")]
+    ImageNotFound(String),
+    #[error("image {0} is being processed, reprocess it once it is done")]
+    ImageProcessing(String),
+    #[error("{0:#}")]
+    Jobs(#[from] anyhow::Error),
+}
+
+impl IntoResponse for ControlError {
+    fn into_response(self) -> Response {
+        let (status, code) = match &self {
+            Self::InvalidBody(rejection) => (rejection.status(), "invalid_body"),
+            Self::InvalidImageName(_) => (StatusCode::BAD_REQUEST, "invalid_image_name"),
+            Self::ImageNotFound(_) => (StatusCode::NOT_FOUND, "image_not_found"),
+            Self::ImageProcessing(_) => (StatusCode::CONFLICT, "image_processing"),
+            Self::Jobs(_) => (StatusCode::INTERNAL_SERVER_ERROR, "job_queue_unavailable"),
+        };
+        if status.is_server_error() {
+            tracing::error!(code, status = status.as_u16(), "{}", self);
+        }
+        Problem::new(status, code, self.to_string()).into_response()
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    paused: bool,
+    queue_depth: i64,
+    current: Option<String>,
+    indexed: i64,
+    dead_letter: i64,
+    // Since the worker started
+    counts: Counts,
+    last_errors: Vec<Failure>,
+}
+
+async fn status_handler(State(api): State<ControlApiState>) -> Result<Json<Status>, ControlError> {
+    let control = &api.control;
+    let (queue_depth, indexed, dead_letter) = tokio::try_join!(
+        control.queue.count(EMBED_IMAGE, "queued"),
+        control.queue.count(EMBED_IMAGE, "done"),
+        control.queue.count(EMBED_IMAGE, "failed"),
+    )?;
+    let current = control
+        .runner
+        .current()
+        .filter(|job| job.kind == EMBED_IMAGE)
+        .and_then(|job| job.key);
+    let state = control.state.lock().unwrap();
+    Ok(Json(Status {
+        paused: control.runner.is_paused(),
+        queue_depth,
+        current,
+        indexed,
+        dead_letter,
+        counts: state.counts,
+        last_errors: state.last_errors.iter().rev().cloned().collect(),
+    }))
+}
+
+// The image being processed is finished first
+async fn pause_handler(State(api): State<ControlApiState>) -> StatusCode {
+    api.control.runner.pause();
+    tracing::info!("Worker paused");
+    StatusCode::NO_CONTENT
+}
+
+async fn resume_handler(State(api): State<ControlApiState>) -> StatusCode {
+    api.control.runner.resume();
+    tracing::info!("Worker resumed");
+    StatusCode::NO_CONTENT
+}
+
+// Scans the images directory now rather than at the next fire time of the schedule
+async fn rescan_handler(State(api): State<ControlApiState>) -> Result<StatusCode, ControlError> {
+    api.control.queue.run_schedule_now(SCAN_IMAGES).await?;
+    api.control.runner.wake();
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Deserialize)]
+struct ReprocessRequest {
+    // Path relative to the images directory, as in the `image_name` of the payload
+    image_name: String,
+}
+
+// Embeds the image again, e.g. after it was replaced, ahead of the images already queued
+async fn reprocess_handler(
+    State(api): State<ControlApiState>,
+    payload: Result<Json<ReprocessRequest>, JsonRejection>,
+) -> Result<StatusCode, ControlError> {
+    let Json(payload) = payload?;
+    let image_name = payload.image_name;
+    let relative = Path::new(&image_name);
+    if !relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return Err(ControlError::InvalidImageName(image_name));
+    }
+    if !api.images_dir.join(relative).is_file() {
+        return Err(ControlError::ImageNotFound(image_name));
+    }
+
+    let job = EmbedImage {
+        image_name: image_name.clone(),
+    }
+    .job(api.control.max_attempts)?
+    .priority(REPROCESS_PRIORITY);
+    if !api.control.queue.requeue(job).await? {
+        return Err(ControlError::ImageProcessing(image_name));
+    }
+    api.control.runner.wake();
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Serialize)]
+struct DeadLetter {
+    image_name: String,
+    attempts: i32,
+    // Prefixed by the stage the image failed at
+    error: Option<String>,
+    at: DateTime<Utc>,
+}
+
+async fn dead_letter_handler(
+    State(api): State<ControlApiState>,
+) -> Result<Json<Vec<DeadLetter>>, ControlError> {
+    let failed = api
+        .control
+        .queue
+        .failed(EMBED_IMAGE, DEAD_LETTER_LIMIT)
+        .await?;
+    Ok(Json(
+        failed
+            .into_iter()
+            .map(|job| DeadLetter {
+                image_name: job.key.unwrap_or_default(),
+                attempts: job.attempts,
+                error: job.error,
+                at: job.failed_at,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Serialize)]
+struct RequeueResponse {
+    requeued: u64,
+}
+
+async fn requeue_dead_letter_handler(
+    State(api): State<ControlApiState>,
+) -> Result<Json<RequeueResponse>, ControlError> {
+    let requeued = api.control.queue.requeue_failed(EMBED_IMAGE).await?;
+    api.control.runner.wake();
+    tracing::info!("Requeued {} dead-lettered images", requeued);
+    Ok(Json(RequeueResponse { requeued }))
+}
@@ -0,0 +1,84 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{extract::State, routing::get, Router};
+use qdrant_client::Qdrant;
+use xlib::{
+    app::health::{check_collection, liveness, probe, Readiness},
+    client::{ClipClient, PostgresClient},
+};
+
+use crate::config::WorkerConfig;
+
+#[derive(Clone)]
+struct HealthState {
+    qdrant_client: Arc<Qdrant>,
+    clip_client: ClipClient,
+    pg_client: PostgresClient,
+    collection: String,
+    vector_size: u64,
+    images_dir: PathBuf,
+    probe_timeout: Duration,
+}
+
+pub fn router(
+    qdrant_client: Arc<Qdrant>,
+    clip_client: ClipClient,
+    pg_client: PostgresClient,
+    config: &WorkerConfig,
+) -> Router {
+    let state = HealthState {
+        qdrant_client,
+        clip_client,
+        pg_client,
+        collection: config.qdrant.collection.clone(),
+        vector_size: config.qdrant.vector_size,
+        images_dir: config.images.dir.clone(),
+        probe_timeout: config.health.probe_timeout(),
+    };
+    Router::new()
+        .route("/api/v1/health/live", get(liveness))
+        .route("/api/v1/health/ready", get(readiness_handler))
+        .with_state(state)
+}
+
+async fn readiness_handler(State(state): State<HealthState>) -> Readiness {
+    let timeout = state.probe_timeout;
+    let (images, database, qdrant, clip) = tokio::join!(
+        probe(timeout, check_images_dir(&state.images_dir)),
+        probe(timeout, async {
+            sqlx::query("SELECT 1")
+                .execute(&*state.pg_client)
+                .await
+                .map(|_| ())
+        }),
+        probe(
+            timeout,
+            check_collection(&state.qdrant_client, &state.collection, state.vector_size)
+        ),
+        probe(timeout, state.clip_client.health_check()),
+    );
+
+    [
+        ("images", images),
+        ("database", database),
+        ("qdrant", qdrant),
+        ("clip", clip),
+    ]
+    .into_iter()
+    .collect()
+}
+
+async fn check_images_dir(dir: &Path) -> Result<()> {
+    let metadata = tokio::fs::metadata(dir)
+        .await
+        .with_context(|| format!("failed to read {}", dir.display()))?;
+    if !metadata.is_dir() {
+        bail!("{} is not a directory", dir.display());
+    }
+    Ok(())
+}
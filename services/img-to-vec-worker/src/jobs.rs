@@ -0,0 +1,97 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use xlib::jobs::{Job, JobError, JobQueue, NewJob};
+
+use crate::{control::Control, pipeline::Pipeline};
+
+// Lists the images directory and queues an `embed_image` job per image not seen yet, run on
+// `images.scan_schedule`
+pub const SCAN_IMAGES: &str = "scan_images";
+// One per image, the path relative to the images root is its key
+pub const EMBED_IMAGE: &str = "embed_image";
+// Reprocessed images go ahead of the images of the scans
+pub const REPROCESS_PRIORITY: i16 = 10;
+
+#[derive(Serialize, Deserialize)]
+pub struct EmbedImage {
+    pub image_name: String,
+}
+
+impl EmbedImage {
+    pub fn job(self, max_attempts: i32) -> anyhow::Result<NewJob> {
+        Ok(NewJob::new(EMBED_IMAGE)
+            .key(self.image_name.clone())
+            .payload(&self)?
+            .max_attempts(max_attempts))
+    }
+}
+
+// Recursively list the files under `dir`, images in sub folders are searchable by folder
+fn list_image_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)?.flatten() {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => match list_image_files(&path) {
+                Ok(mut sub_files) => files.append(&mut sub_files),
+                Err(e) => warn!("Error reading {} directory: {}", path.display(), e),
+            },
+            Ok(file_type) if file_type.is_file() => files.push(path),
+            _ => {}
+        }
+    }
+    Ok(files)
+}
+
+// An image already has a job whatever its status, so it is embedded once until it is reprocessed
+pub async fn scan_images(
+    images_dir: &Path,
+    queue: &JobQueue,
+    control: &Control,
+    max_attempts: i32,
+) -> Result<(), JobError> {
+    let image_paths = list_image_files(images_dir)
+        .with_context(|| format!("failed to read {} directory", images_dir.display()))?;
+    let jobs = image_paths
+        .into_iter()
+        .filter_map(|path| Some(path.strip_prefix(images_dir).ok()?.to_str()?.to_string()))
+        .map(|image_name| {
+            let payload = serde_json::json!({ "image_name": image_name });
+            (image_name, payload)
+        })
+        .collect();
+
+    let count = queue.enqueue_keys(EMBED_IMAGE, jobs, max_attempts).await?;
+    if count > 0 {
+        info!("Found {} unprocessed images", count);
+    }
+    control.discovered(count);
+
+    let queued = queue.count(EMBED_IMAGE, "queued").await?;
+    #[allow(clippy::cast_precision_loss)]
+    metrics::gauge!("worker_images_queued").set(queued as f64);
+    Ok(())
+}
+
+pub async fn embed_image(job: Job, pipeline: &Pipeline, control: &Control) -> Result<(), JobError> {
+    let EmbedImage { image_name } = job.payload().map_err(JobError::permanent)?;
+    info!("Processing image {}", image_name);
+    match pipeline.process(&image_name).await {
+        Ok(()) => {
+            control.complete();
+            Ok(())
+        }
+        Err(e) => {
+            let retried = e.retried;
+            control.fail(image_name, &e);
+            if retried {
+                Err(JobError::retry(e))
+            } else {
+                Err(JobError::permanent(e))
+            }
+        }
+    }
+}
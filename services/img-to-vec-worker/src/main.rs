@@ -1,18 +1,129 @@
 #![allow(clippy::redundant_pub_crate)]
 
-use qdrant_client::qdrant::{
-    CreateCollectionBuilder, Distance, PointStruct, UpsertPointsBuilder, VectorParamsBuilder,
-};
+use axum::Router;
+use metrics_exporter_prometheus::PrometheusHandle;
+use qdrant_client::qdrant::{CreateCollectionBuilder, Distance, VectorParamsBuilder};
 use qdrant_client::Qdrant;
 
-use base64::Engine;
-use std::time::Duration;
+use config::{DatabaseConfig, WorkerConfig};
+use control::Control;
+use pipeline::Pipeline;
+use safety::{SafetyClassifier, SafetyConfig};
+use std::{net::SocketAddrV4, sync::Arc, time::Duration};
 use tracing::{info, warn};
-use uuid::Uuid;
-async fn start_background_worker() {
-    let qdrant_client = Qdrant::from_url("http://qdrant:6334").build().unwrap();
-    let collection_name = "clip_images_collection";
-    let vector_size = 512;
+use xlib::{
+    app::{
+        graceful_shutdown::shutdown_signal, middleware::with_request_tracing, serve::serve_service,
+    },
+    client::{ClipClient, ClipClientConfig, PostgresClient, PostgresClientConfig},
+    collection::create_payload_indexes,
+    jobs::{JobQueue, Runner, RunnerHandle},
+};
+use zero_shot::Annotator;
+
+mod config;
+mod control;
+mod health;
+mod jobs;
+mod metadata;
+mod pipeline;
+mod preprocess;
+mod safety;
+mod tagging;
+mod zero_shot;
+
+// First wait before the content safety setup is retried, doubled at every attempt
+const SAFETY_SETUP_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_SAFETY_SETUP_BACKOFF: Duration = Duration::from_secs(60);
+
+// Serves `router` in the background, the worker keeps running without it
+fn spawn_service(router: Router, addr: SocketAddrV4, service_name: &'static str) {
+    tokio::spawn(async move {
+        if let Err(e) = serve_service(with_request_tracing(router), addr, service_name).await {
+            warn!("{:#}", e);
+        }
+    });
+}
+
+async fn init_db(config: &DatabaseConfig) -> PostgresClient {
+    let db_config = PostgresClientConfig {
+        hostname: config.hostname.clone(),
+        port: config.port,
+        user: config.user.clone(),
+        password: config.password.clone(),
+        db_name: config.name.clone(),
+        max_connections: Some(config.max_connections),
+        acquire_timeout: Some(config.acquire_timeout()),
+    };
+    PostgresClient::build(&db_config).await.unwrap()
+}
+
+// Safe search hides the images without a safety score, so no image is indexed before the
+// classifier is set up, e.g. while the CLIP model is still loading
+async fn setup_safety_classifier(
+    clip_client: &ClipClient,
+    config: &SafetyConfig,
+) -> SafetyClassifier {
+    let mut backoff = SAFETY_SETUP_BACKOFF;
+    loop {
+        match SafetyClassifier::new(clip_client, config).await {
+            Ok(classifier) => return classifier,
+            Err(e) => {
+                warn!(
+                    "Failed to set up content safety, retrying in {:?}: {:#}",
+                    backoff, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_SAFETY_SETUP_BACKOFF);
+            }
+        }
+    }
+}
+
+async fn start_background_worker(config: WorkerConfig, metrics_handle: PrometheusHandle) {
+    let qdrant_client = Arc::new(
+        Qdrant::from_url(&config.qdrant.url)
+            .timeout(config.qdrant.timeout())
+            .connect_timeout(config.qdrant.connect_timeout())
+            .build()
+            .unwrap(),
+    );
+    let clip_client = ClipClient::new(&ClipClientConfig {
+        url: config.clip.url.clone(),
+        timeout: config.clip.timeout(),
+        connect_timeout: config.clip.connect_timeout(),
+        pool_max_idle_per_host: None,
+        batch_size: None,
+    })
+    .unwrap();
+    let pg_client = init_db(&config.database).await;
+
+    let http_addr = config.public_http.socket_addr();
+    let router = health::router(
+        qdrant_client.clone(),
+        clip_client.clone(),
+        pg_client.clone(),
+        &config,
+    )
+    .merge(xlib::app::metrics::router(metrics_handle));
+    spawn_service(router, http_addr, "img-to-vec worker health and metrics");
+
+    // The images are queued in the job table, several workers can share it
+    let queue = JobQueue::new(pg_client.into_inner());
+    let runner_handle = RunnerHandle::default();
+    let max_attempts = config.jobs.max_attempts;
+    let control = Arc::new(Control::new(
+        queue.clone(),
+        runner_handle.clone(),
+        max_attempts,
+    ));
+    let control_addr = config.private_http.socket_addr();
+    let control_router = control::router(control.clone(), config.images.dir.clone());
+    spawn_service(control_router, control_addr, "img-to-vec worker control");
+
+    let collection_name = config.qdrant.collection.as_str();
+    let vector_size = config.qdrant.vector_size;
+    let images_dir = config.images.dir.as_path();
     // Check if collection exists first
     if let Ok(collections) = qdrant_client.list_collections().await {
         if !collections
@@ -40,128 +151,91 @@ async fn start_background_worker() {
     }
     info!("Collection created");
 
-    let http_client = reqwest::Client::builder().build().unwrap();
-
-    // In MVP we only deploy one container but in the future we might need to scale up the worker
-    // so we should use external database to record the processed images
-    let mut completed_images = Vec::new();
-
-    // Set up graceful shutdown channel
-    let (shutdown_tx, mut shutdown_rx) = tokio::sync::broadcast::channel(1);
-
-    // Handle Ctrl+C
-    let shutdown_tx_clone = shutdown_tx.clone();
-    tokio::spawn(async move {
-        if tokio::signal::ctrl_c().await.is_ok() {
-            info!("Received shutdown signal");
-            let _ = shutdown_tx_clone.send(());
+    for (field_name, result) in create_payload_indexes(&qdrant_client, collection_name).await {
+        if let Err(e) = result {
+            warn!("Failed to create payload index on {}: {}", field_name, e);
         }
-    });
+    }
+    info!("Payload indexes created");
 
-    loop {
-        tokio::select! {
-            _ = shutdown_rx.recv() => {
-                info!("Shutting down worker gracefully...");
-                break;
-            }
-            _ = tokio::time::sleep(Duration::from_secs(10)) => {
-                match std::fs::read_dir("/images") {
-                    Ok(entries) => {
-                        for (i, entry) in entries.flatten().enumerate() {
-                            if let Ok(file_name) = entry.file_name().into_string() {
-                                // check if the image is already processed
-                                if completed_images.contains(&file_name) {
-                                    continue;
-                                } else {
-                                    info!("Found un processed image file #{}: {}", i, file_name);
-                                    // TODO: process the image
-                                    let image_path = entry.path();
-                                    if let Ok(image_data) = std::fs::read(&image_path) {
-                                        // let base64_image = base64::encode(&image_data);
-                                        let base64_image = base64::engine::general_purpose::STANDARD.encode(&image_data);
-                                        info!("Generated base64 for image: {}", file_name);
-                                        let data = serde_json::json!({
-                                            "image_base64": base64_image
-                                        });
-
-                                        match http_client.request(reqwest::Method::POST, "http://clip-model:8000/api/v1/clip/image-to-vector")
-                                            .json(&data)
-                                            .send()
-                                            .await {
-                                                Ok(response) => {
-                                                    match response.text().await {
-                                                        Ok(body) => {
-                                                            info!("Got vector response for image: {}", file_name);
-                                                            // Parse the response body as JSON
-                                                            if let Ok(vector_response) = serde_json::from_str::<serde_json::Value>(&body) {
-                                                                if let Some(vector) = vector_response.get("vector") {
-                                                                    if let Some(vector_array) = vector.as_array() {
-                                                                        // Convert JSON array to Vec<f32>
-                                                                        let vector_data: Vec<f32> = vector_array
-                                                                            .iter()
-                                                                            .filter_map(|v| v.as_f64().map(|x| x as f32))
-                                                                            .collect();
-
-                                                                        info!("Successfully parsed vector with {} dimensions", vector_data.len());
-                                                                        let point = PointStruct::new(
-                                                                            Uuid::new_v5(&Uuid::NAMESPACE_URL, file_name.as_bytes()).to_string(),
-                                                                            vector_data,
-                                                                            [("image_name", file_name.clone().into())]
-                                                                        );
-                                                                        qdrant_client.upsert_points(UpsertPointsBuilder::new(collection_name, vec![point]).wait(true)).await.unwrap();
-                                                                        info!("Upserted point into Qdrant");
-                                                                    } else {
-                                                                        warn!("Vector field is not an array");
-                                                                    }
-                                                                } else {
-                                                                    warn!("Response missing vector field");
-                                                                }
-                                                            } else {
-                                                                warn!("Failed to parse response as JSON: {}", body);
-                                                            }
-
-                                                        }
-                                                        Err(e) => {
-                                                            warn!("Failed to get response body: {}", e);
-                                                        }
-                                                    }
-                                                }
-                                                Err(e) => {
-                                                    warn!("Failed to send request to CLIP model: {}", e);
-                                                }
-                                        };
-
-
-                                    } else {
-                                        warn!("Failed to read image file: {}", file_name);
-                                        continue;
-                                    }
-                                    completed_images.push(file_name);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        warn!("Error reading /images directory: {}", e);
-                    }
-                }
-            }
+    // Payload computed from the image vector: auto-tagging and content safety
+    let mut annotators: Vec<Box<dyn Annotator>> = Vec::new();
+    if config.tagging.labels.is_empty() {
+        info!("Auto-tagging disabled");
+    } else {
+        match tagging::Tagger::new(&clip_client, &config.tagging).await {
+            Ok(tagger) => annotators.push(Box::new(tagger)),
+            Err(e) => warn!(
+                "Failed to set up auto-tagging, images won't be tagged: {:#}",
+                e
+            ),
+        }
+    }
+    // Ctrl+C or SIGTERM, which Docker sends on stop
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    tokio::select! {
+        classifier = setup_safety_classifier(&clip_client, &config.safety) => {
+            annotators.push(Box::new(classifier));
+        }
+        () = &mut shutdown => {
+            info!("Received shutdown signal before the worker started");
+            return;
+        }
+    }
+    for annotator in &annotators {
+        match zero_shot::reannotate_outdated_points(
+            &qdrant_client,
+            collection_name,
+            annotator.as_ref(),
+        )
+        .await
+        {
+            Ok(count) => info!("Recomputed {} of {} images", annotator.name(), count),
+            Err(e) => warn!("Failed to recompute {}: {:#}", annotator.name(), e),
         }
     }
 
+    let pipeline = Pipeline {
+        qdrant_client: qdrant_client.clone(),
+        clip_client,
+        collection: collection_name.to_string(),
+        images_dir: images_dir.to_path_buf(),
+        preprocess: config.preprocess,
+        annotators,
+    };
+
+    let (pipeline, control, queue) = (&pipeline, control.as_ref(), &queue);
+    let runner_config = config.jobs.runner_config(config.shutdown.drain_timeout());
+    Runner::new(queue.clone(), runner_config, &runner_handle)
+        .on(jobs::EMBED_IMAGE, move |job| {
+            jobs::embed_image(job, pipeline, control)
+        })
+        .on(jobs::SCAN_IMAGES, move |_| {
+            jobs::scan_images(images_dir, queue, control, max_attempts)
+        })
+        .schedule(jobs::SCAN_IMAGES, config.images.scan_schedule())
+        .run(shutdown)
+        .await;
+
     info!("Worker shutdown complete");
 }
 
 fn main() {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_writer(std::io::stdout)
-        .init();
-    // Log when the program starts
-    info!("Starting img-to-vec worker...");
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap()
-        .block_on(start_background_worker());
+        .block_on(async {
+            // within the runtime, the OTLP exporter runs on it
+            let tracing = xlib::app::tracing::setup("img-to-vec-worker", "info");
+            // Log when the program starts
+            info!("Starting img-to-vec worker...");
+            let metrics_handle = xlib::app::metrics::setup();
+            let config =
+                WorkerConfig::load().unwrap_or_else(|e| panic!("invalid configuration: {e}"));
+
+            start_background_worker(config, metrics_handle).await;
+            tracing.shutdown().await;
+        });
 }
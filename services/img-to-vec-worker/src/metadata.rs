@@ -0,0 +1,323 @@
+use std::{fs, io::Cursor, path::Path};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset, NaiveDate, SecondsFormat, Utc};
+use exif::{Exif, In, Tag, Value};
+use image::ImageFormat;
+use qdrant_client::Payload;
+use serde::Serialize;
+
+use crate::preprocess::PreprocessedImage;
+
+// Stored as the payload of the image point in Qdrant
+#[derive(Serialize)]
+pub struct ImageMetadata {
+    // Path relative to the images root, e.g. `2023/beach/IMG_2041.jpg`
+    pub image_name: String,
+    // Folder of the image relative to the images root, `""` for the root itself
+    pub folder: String,
+    pub width: u32,
+    pub height: u32,
+    pub aspect_ratio: f64,
+    pub format: String,
+    pub file_size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps_latitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps_longitude: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps_altitude: Option<f64>,
+    // Same position as a Qdrant geo point, for geo radius and bounding box filters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<GeoPoint>,
+    // Lowercase words of the folders and file name, e.g. `2023 beach img 2041`, for the lexical
+    // half of hybrid search. The web server tokenizes the queries the same way.
+    pub search_text: String,
+}
+
+#[derive(Serialize)]
+pub struct GeoPoint {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl ImageMetadata {
+    pub fn extract(
+        image_name: &str,
+        image_path: &Path,
+        image_data: &[u8],
+        image: &PreprocessedImage,
+    ) -> Self {
+        let file_metadata = fs::metadata(image_path).ok();
+        let modified_at = file_metadata
+            .as_ref()
+            .and_then(|m| m.modified().ok())
+            .map(|t| DateTime::<Utc>::from(t).to_rfc3339_opts(SecondsFormat::Secs, true));
+        let file_size = file_metadata.map_or(image_data.len() as u64, |m| m.len());
+
+        // most formats don't carry EXIF at all, so a missing block is not an error
+        let exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(image_data))
+            .ok();
+        let exif = exif.as_ref();
+
+        let folder = Path::new(image_name)
+            .parent()
+            .and_then(|p| p.to_str())
+            .unwrap_or_default();
+
+        let gps_latitude =
+            exif.and_then(|e| gps_coordinate(e, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S'));
+        let gps_longitude =
+            exif.and_then(|e| gps_coordinate(e, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W'));
+        let location = gps_latitude
+            .zip(gps_longitude)
+            .filter(|(lat, lon)| (-90.0..=90.0).contains(lat) && (-180.0..=180.0).contains(lon))
+            .map(|(lat, lon)| GeoPoint { lat, lon });
+
+        Self {
+            image_name: image_name.to_string(),
+            folder: folder.to_string(),
+            width: image.width,
+            height: image.height,
+            aspect_ratio: f64::from(image.width) / f64::from(image.height.max(1)),
+            format: format_name(image.format),
+            file_size,
+            modified_at,
+            captured_at: exif.and_then(captured_at),
+            camera_make: exif.and_then(|e| ascii_field(e, Tag::Make)),
+            camera_model: exif.and_then(|e| ascii_field(e, Tag::Model)),
+            gps_latitude,
+            gps_longitude,
+            gps_altitude: exif.and_then(gps_altitude),
+            location,
+            search_text: search_text(image_name),
+        }
+    }
+
+    pub fn into_payload(self) -> Result<Payload> {
+        let value = serde_json::to_value(self).context("failed to serialize image metadata")?;
+        Payload::try_from(value).context("failed to convert image metadata to payload")
+    }
+}
+
+// e.g. `ImageFormat::Jpeg` -> "jpeg"
+fn format_name(format: ImageFormat) -> String {
+    format!("{format:?}").to_lowercase()
+}
+
+// Words of the path without the extension, `2023/beach/IMG_2041.jpg` -> `2023 beach img 2041`
+fn search_text(image_name: &str) -> String {
+    Path::new(image_name)
+        .with_extension("")
+        .to_string_lossy()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn ascii_field(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => {
+            let value = String::from_utf8_lossy(values.first()?);
+            let value = value.trim_matches(|c: char| c.is_whitespace() || c == '\0');
+            (!value.is_empty()).then(|| value.to_string())
+        }
+        _ => None,
+    }
+}
+
+// EXIF dates carry no time zone, dates without an `OffsetTimeOriginal` are assumed to be UTC
+fn captured_at(exif: &Exif) -> Option<String> {
+    let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)?;
+    let Value::Ascii(values) = &field.value else {
+        return None;
+    };
+    let mut date_time = exif::DateTime::from_ascii(values.first()?).ok()?;
+    if let Some(Value::Ascii(offset)) = exif
+        .get_field(Tag::OffsetTimeOriginal, In::PRIMARY)
+        .map(|f| &f.value)
+    {
+        if let Some(offset) = offset.first() {
+            let _ = date_time.parse_offset(offset);
+        }
+    }
+
+    let naive = NaiveDate::from_ymd_opt(
+        i32::from(date_time.year),
+        u32::from(date_time.month),
+        u32::from(date_time.day),
+    )?
+    .and_hms_opt(
+        u32::from(date_time.hour),
+        u32::from(date_time.minute),
+        u32::from(date_time.second),
+    )?;
+    let offset = FixedOffset::east_opt(i32::from(date_time.offset.unwrap_or(0)) * 60)?;
+    let captured_at = naive.and_local_timezone(offset).single()?;
+
+    Some(
+        captured_at
+            .with_timezone(&Utc)
+            .to_rfc3339_opts(SecondsFormat::Secs, true),
+    )
+}
+
+// Degrees/minutes/seconds to signed decimal degrees
+fn gps_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative_ref: u8) -> Option<f64> {
+    let Value::Rational(dms) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    if dms.len() < 3 || dms.iter().any(|r| r.denom == 0) {
+        return None;
+    }
+    let degrees = dms[0].to_f64() + dms[1].to_f64() / 60.0 + dms[2].to_f64() / 3600.0;
+
+    let is_negative = match &exif.get_field(ref_tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => values.first()?.first() == Some(&negative_ref),
+        _ => false,
+    };
+
+    Some(if is_negative { -degrees } else { degrees })
+}
+
+fn gps_altitude(exif: &Exif) -> Option<f64> {
+    let Value::Rational(altitude) = &exif.get_field(Tag::GPSAltitude, In::PRIMARY)?.value else {
+        return None;
+    };
+    let altitude = altitude.first().filter(|r| r.denom != 0)?.to_f64();
+
+    // a reference of 1 means below sea level
+    let below_sea_level = exif
+        .get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+        == Some(1);
+
+    Some(if below_sea_level { -altitude } else { altitude })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{experimental::Writer, Field};
+
+    // EXIF block holding `fields`, read back the same way as from an image file
+    fn exif_of(fields: &[Field]) -> Exif {
+        let mut writer = Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut data = Cursor::new(Vec::new());
+        writer.write(&mut data, false).unwrap();
+        exif::Reader::new().read_raw(data.into_inner()).unwrap()
+    }
+
+    fn ascii(tag: Tag, value: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![value.as_bytes().to_vec()]),
+        }
+    }
+
+    // 25°2'30", a denominator of 0 makes it invalid
+    fn dms(tag: Tag, seconds_denom: u32) -> Field {
+        let rational = |num, denom| exif::Rational { num, denom };
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Rational(vec![
+                rational(25, 1),
+                rational(2, 1),
+                rational(30, seconds_denom),
+            ]),
+        }
+    }
+
+    fn latitude(exif: &Exif) -> Option<f64> {
+        gps_coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, b'S')
+    }
+
+    fn longitude(exif: &Exif) -> Option<f64> {
+        gps_coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, b'W')
+    }
+
+    #[test]
+    fn gps_coordinate_is_negative_south_and_west() {
+        let degrees = 25.0 + 2.0 / 60.0 + 30.0 / 3600.0;
+        let north_east = exif_of(&[
+            dms(Tag::GPSLatitude, 1),
+            ascii(Tag::GPSLatitudeRef, "N"),
+            dms(Tag::GPSLongitude, 1),
+            ascii(Tag::GPSLongitudeRef, "E"),
+        ]);
+        assert_eq!(latitude(&north_east), Some(degrees));
+        assert_eq!(longitude(&north_east), Some(degrees));
+
+        let south_west = exif_of(&[
+            dms(Tag::GPSLatitude, 1),
+            ascii(Tag::GPSLatitudeRef, "S"),
+            dms(Tag::GPSLongitude, 1),
+            ascii(Tag::GPSLongitudeRef, "W"),
+        ]);
+        assert_eq!(latitude(&south_west), Some(-degrees));
+        assert_eq!(longitude(&south_west), Some(-degrees));
+    }
+
+    #[test]
+    fn gps_coordinate_needs_a_reference_and_valid_rationals() {
+        let without_ref = exif_of(&[dms(Tag::GPSLatitude, 1)]);
+        assert_eq!(latitude(&without_ref), None);
+
+        let zero_denom = exif_of(&[dms(Tag::GPSLatitude, 0), ascii(Tag::GPSLatitudeRef, "N")]);
+        assert_eq!(latitude(&zero_denom), None);
+    }
+
+    // Same words as `hybrid::tokenize` in the web server gives for the query `2023/beach/IMG_2041`
+    #[test]
+    fn search_text_splits_like_the_web_server() {
+        assert_eq!(
+            search_text("2023/beach/IMG_2041.jpg"),
+            "2023 beach img 2041"
+        );
+        assert_eq!(search_text("Été/Plage-Bleue.png"), "été plage bleue");
+        assert_eq!(search_text("IMG_2041.jpg"), "img 2041");
+    }
+
+    #[test]
+    fn captured_at_applies_the_offset() {
+        let exif = exif_of(&[
+            ascii(Tag::DateTimeOriginal, "2023:07:14 18:30:00"),
+            ascii(Tag::OffsetTimeOriginal, "+08:00"),
+        ]);
+        assert_eq!(captured_at(&exif).as_deref(), Some("2023-07-14T10:30:00Z"));
+
+        let exif = exif_of(&[
+            ascii(Tag::DateTimeOriginal, "2023:07:14 18:30:00"),
+            ascii(Tag::OffsetTimeOriginal, "-05:30"),
+        ]);
+        assert_eq!(captured_at(&exif).as_deref(), Some("2023-07-15T00:00:00Z"));
+    }
+
+    #[test]
+    fn captured_at_without_offset_is_utc() {
+        let exif = exif_of(&[ascii(Tag::DateTimeOriginal, "2023:07:14 18:30:00")]);
+        assert_eq!(captured_at(&exif).as_deref(), Some("2023-07-14T18:30:00Z"));
+    }
+
+    #[test]
+    fn captured_at_skips_an_invalid_date() {
+        let exif = exif_of(&[ascii(Tag::DateTimeOriginal, "0000:00:00 00:00:00")]);
+        assert_eq!(captured_at(&exif), None);
+    }
+}
@@ -0,0 +1,157 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+
+use base64::Engine;
+use qdrant_client::{
+    qdrant::{PointStruct, UpsertPointsBuilder},
+    Payload, Qdrant,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use uuid::Uuid;
+use xlib::{
+    app::metrics::observe,
+    client::{ClipClient, ClipError},
+};
+
+use crate::{
+    metadata, preprocess,
+    preprocess::{PreprocessConfig, PreprocessedImage},
+    zero_shot::Annotator,
+};
+
+// Step of the pipeline an image failed at
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Read,
+    Preprocess,
+    Payload,
+    Embed,
+    Upsert,
+}
+
+impl Stage {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Preprocess => "preprocess",
+            Self::Payload => "payload",
+            Self::Embed => "embed",
+            Self::Upsert => "upsert",
+        }
+    }
+
+    // Failures of the other stages are retried with a backoff until the image runs out of
+    // attempts. An image that doesn't decode goes to the dead-letter list right away, until it
+    // is requeued.
+    pub const fn is_retried(self) -> bool {
+        !matches!(self, Self::Preprocess)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{stage}: {message}", stage = .stage.as_str())]
+pub struct ImageError {
+    pub stage: Stage,
+    pub message: String,
+    // Whether the job is attempted again, see `Stage::is_retried`
+    pub retried: bool,
+}
+
+impl ImageError {
+    fn new(stage: Stage, error: impl std::fmt::Display) -> Self {
+        Self {
+            stage,
+            message: format!("{error:#}"),
+            retried: stage.is_retried(),
+        }
+    }
+
+    // Only an image the CLIP model rejects is dead-lettered right away, the model being down or
+    // overloaded is retried like the other stages
+    fn embed(error: &ClipError) -> Self {
+        Self {
+            retried: !error.is_rejection(),
+            ..Self::new(Stage::Embed, error)
+        }
+    }
+}
+
+// Turns an image file into a point of the collection
+pub struct Pipeline {
+    pub qdrant_client: Arc<Qdrant>,
+    pub clip_client: ClipClient,
+    pub collection: String,
+    pub images_dir: PathBuf,
+    pub preprocess: PreprocessConfig,
+    // Payload computed from the image vector: auto-tagging and content safety
+    pub annotators: Vec<Box<dyn Annotator>>,
+}
+
+impl Pipeline {
+    // `file_name` is the path of the image relative to the images root
+    pub async fn process(&self, file_name: &str) -> Result<(), ImageError> {
+        let start = Instant::now();
+        // The file IO and the decoding would block the runtime thread, and with it the heartbeats
+        // of the other jobs
+        let (image, mut payload) = tokio::task::spawn_blocking({
+            let image_path = self.images_dir.join(file_name);
+            let file_name = file_name.to_string();
+            let config = self.preprocess.clone();
+            move || load(&file_name, &image_path, &config)
+        })
+        .await
+        .map_err(|e| ImageError::new(Stage::Preprocess, e))??;
+        info!(
+            "Preprocessed image {} ({}x{})",
+            file_name, image.width, image.height
+        );
+
+        let base64_image = base64::engine::general_purpose::STANDARD.encode(&image.jpeg);
+        let vector = self
+            .clip_client
+            .image_to_vector(&base64_image)
+            .await
+            .map_err(|e| ImageError::embed(&e))?;
+        info!("Embedded image {} ({} dimensions)", file_name, vector.len());
+        for annotator in &self.annotators {
+            annotator.annotate(&mut payload, &vector);
+        }
+
+        let point = PointStruct::new(
+            Uuid::new_v5(&Uuid::NAMESPACE_URL, file_name.as_bytes()).to_string(),
+            vector,
+            payload,
+        );
+        let upsert = self.qdrant_client.upsert_points(
+            UpsertPointsBuilder::new(self.collection.as_str(), vec![point]).wait(true),
+        );
+        observe("qdrant", "upsert_points", upsert)
+            .await
+            .map_err(|e| ImageError::new(Stage::Upsert, e))?;
+        info!("Upserted image {} into Qdrant", file_name);
+
+        metrics::histogram!("worker_image_processing_duration_seconds")
+            .record(start.elapsed().as_secs_f64());
+        Ok(())
+    }
+}
+
+// Read and decode the image, and extract its metadata
+fn load(
+    file_name: &str,
+    image_path: &Path,
+    config: &PreprocessConfig,
+) -> Result<(PreprocessedImage, Payload), ImageError> {
+    let image_data = std::fs::read(image_path).map_err(|e| ImageError::new(Stage::Read, e))?;
+    let image = preprocess::preprocess(&image_data, config)
+        .map_err(|e| ImageError::new(Stage::Preprocess, e))?;
+    let payload = metadata::ImageMetadata::extract(file_name, image_path, &image_data, &image)
+        .into_payload()
+        .map_err(|e| ImageError::new(Stage::Payload, e))?;
+    Ok((image, payload))
+}
@@ -0,0 +1,122 @@
+use std::io::Cursor;
+
+use confique::Config;
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageDecoder, ImageError,
+    ImageFormat, ImageReader,
+};
+
+const JPEG_QUALITY: u8 = 90;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PreprocessError {
+    #[error("unrecognized image format")]
+    UnknownFormat,
+    #[error("failed to decode image: {0}")]
+    Decode(#[source] ImageError),
+    #[error("failed to encode image: {0}")]
+    Encode(#[source] ImageError),
+}
+
+#[derive(Config, Clone)]
+pub struct PreprocessConfig {
+    // Longest edge (in pixels) of the image sent to the CLIP service
+    #[config(
+        default = 512,
+        env = "IMAGE_MAX_EDGE",
+        validate(*max_edge > 0, "must be positive")
+    )]
+    pub max_edge: u32,
+}
+
+pub struct PreprocessedImage {
+    // JPEG encoded RGB image, ready to be sent to the CLIP service
+    pub jpeg: Vec<u8>,
+    pub format: ImageFormat,
+    // Size of the original image once the EXIF orientation is applied
+    pub width: u32,
+    pub height: u32,
+}
+
+// Decode the raw file, apply the EXIF orientation, convert to RGB and downscale it so that
+// the longest edge is at most `max_edge`. CLIP resizes to 224px anyway, so there is no point
+// in shipping the original file over the network.
+pub fn preprocess(
+    image_data: &[u8],
+    config: &PreprocessConfig,
+) -> Result<PreprocessedImage, PreprocessError> {
+    let reader = ImageReader::new(Cursor::new(image_data))
+        .with_guessed_format()
+        .map_err(|e| PreprocessError::Decode(ImageError::IoError(e)))?;
+    let Some(format) = reader.format() else {
+        return Err(PreprocessError::UnknownFormat);
+    };
+
+    let mut decoder = reader.into_decoder().map_err(PreprocessError::Decode)?;
+    let orientation = decoder.orientation().map_err(PreprocessError::Decode)?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(PreprocessError::Decode)?;
+    image.apply_orientation(orientation);
+    let (width, height) = (image.width(), image.height());
+
+    if image.width().max(image.height()) > config.max_edge {
+        image = image.resize(config.max_edge, config.max_edge, FilterType::Triangle);
+    }
+    let rgb = image.into_rgb8();
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, JPEG_QUALITY)
+        .encode_image(&rgb)
+        .map_err(PreprocessError::Encode)?;
+
+    Ok(PreprocessedImage {
+        jpeg,
+        format,
+        width,
+        height,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn preprocess_downscales_to_max_edge() {
+        let config = PreprocessConfig { max_edge: 512 };
+        let image = preprocess(&png(1000, 500), &config).unwrap();
+        // the size of the original is kept for the payload
+        assert_eq!(
+            (image.format, image.width, image.height),
+            (ImageFormat::Png, 1000, 500)
+        );
+
+        let jpeg = image::load_from_memory_with_format(&image.jpeg, ImageFormat::Jpeg).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (512, 256));
+    }
+
+    #[test]
+    fn preprocess_keeps_a_small_image_as_is() {
+        let config = PreprocessConfig { max_edge: 512 };
+        let image = preprocess(&png(300, 200), &config).unwrap();
+        let jpeg = image::load_from_memory_with_format(&image.jpeg, ImageFormat::Jpeg).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (300, 200));
+    }
+
+    #[test]
+    fn preprocess_rejects_an_unknown_format() {
+        let config = PreprocessConfig { max_edge: 512 };
+        assert!(matches!(
+            preprocess(b"not an image", &config),
+            Err(PreprocessError::UnknownFormat)
+        ));
+    }
+}
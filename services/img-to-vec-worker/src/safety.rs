@@ -0,0 +1,163 @@
+use anyhow::Result;
+use confique::Config;
+use qdrant_client::Payload;
+
+use xlib::client::ClipClient;
+
+use crate::{
+    config,
+    zero_shot::{self, Annotator, LabelSet},
+};
+
+pub const SAFETY_FIELD: &str = "safety";
+pub const SAFETY_FLAGGED_FIELD: &str = "safety_flagged";
+pub const SAFETY_VERSION_FIELD: &str = "safety_version";
+
+// Prompts may contain commas, so `SAFETY_UNSAFE_PROMPTS` and `SAFETY_SAFE_PROMPTS` are
+// semicolon separated lists
+#[derive(Config)]
+pub struct SafetyConfig {
+    #[config(
+        default = [
+            "a photo containing nudity",
+            "a sexually explicit photo",
+            "a photo of graphic violence",
+            "a photo of gore and blood",
+            "a photo of a dead body",
+            "a photo of drug use",
+        ],
+        env = "SAFETY_UNSAFE_PROMPTS",
+        parse_env = config::list_by_semicolon,
+        validate = config::not_empty_list
+    )]
+    pub unsafe_prompts: Vec<String>,
+    // Counterweight to the unsafe prompts, otherwise every image is close to one of them
+    #[config(
+        default = [
+            "a safe for work photo",
+            "an ordinary everyday photo",
+            "a photo of a landscape",
+            "a photo of people",
+            "a photo of an object",
+        ],
+        env = "SAFETY_SAFE_PROMPTS",
+        parse_env = config::list_by_semicolon,
+        validate = config::not_empty_list
+    )]
+    pub safe_prompts: Vec<String>,
+    // Images with a safety score above the threshold are flagged and hidden by safe search
+    #[config(default = 0.5, env = "SAFETY_THRESHOLD")]
+    pub threshold: f32,
+}
+
+pub struct SafetyClassifier {
+    // Unsafe prompts first, then the safe ones
+    label_set: LabelSet,
+    unsafe_count: usize,
+    threshold: f32,
+    version: String,
+}
+
+impl SafetyClassifier {
+    pub async fn new(clip_client: &ClipClient, config: &SafetyConfig) -> Result<Self> {
+        let version = zero_shot::version(&[
+            &config.unsafe_prompts.join(";"),
+            &config.safe_prompts.join(";"),
+            &config.threshold.to_string(),
+        ]);
+        let unsafe_count = config.unsafe_prompts.len();
+        let prompts = config
+            .unsafe_prompts
+            .iter()
+            .chain(&config.safe_prompts)
+            .cloned()
+            .collect();
+        let label_set = LabelSet::embed(clip_client, prompts, "{}").await?;
+
+        Ok(Self {
+            label_set,
+            unsafe_count,
+            threshold: config.threshold,
+            version,
+        })
+    }
+
+    // Probability that the image shows one of the unsafe concepts
+    pub fn score(&self, image_vector: &[f32]) -> f32 {
+        self.label_set.probabilities(image_vector)[..self.unsafe_count]
+            .iter()
+            .sum()
+    }
+}
+
+impl Annotator for SafetyClassifier {
+    fn name(&self) -> &'static str {
+        "safety"
+    }
+
+    fn version_field(&self) -> &'static str {
+        SAFETY_VERSION_FIELD
+    }
+
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    fn annotate(&self, payload: &mut Payload, image_vector: &[f32]) {
+        let score = self.score(image_vector);
+        payload.insert(SAFETY_FIELD, score);
+        payload.insert(SAFETY_FLAGGED_FIELD, score >= self.threshold);
+        payload.insert(SAFETY_VERSION_FIELD, self.version.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two unsafe prompts then a safe one
+    fn classifier(threshold: f32) -> SafetyClassifier {
+        SafetyClassifier {
+            label_set: LabelSet::from_vectors(
+                &["gore", "nudity", "landscape"],
+                vec![
+                    vec![1.0, 0.0, 0.0],
+                    vec![0.0, 1.0, 0.0],
+                    vec![0.0, 0.0, 1.0],
+                ],
+            ),
+            unsafe_count: 2,
+            threshold,
+            version: String::new(),
+        }
+    }
+
+    fn flagged(classifier: &SafetyClassifier, image_vector: &[f32]) -> bool {
+        let mut payload = Payload::new();
+        classifier.annotate(&mut payload, image_vector);
+        serde_json::Value::from(payload)[SAFETY_FLAGGED_FIELD]
+            .as_bool()
+            .unwrap()
+    }
+
+    #[test]
+    fn score_sums_the_unsafe_probabilities() {
+        // logits of 1, 1 and 0: e / (2e + 1) each for the unsafe prompts
+        let score = classifier(0.5).score(&[0.01, 0.01, 0.0]);
+        let e = std::f32::consts::E;
+        assert!((score - 2.0 * e / (2.0 * e + 1.0)).abs() < 1e-5);
+
+        let score = classifier(0.5).score(&[0.0, 0.0, 0.1]);
+        assert!(score < 0.001);
+    }
+
+    #[test]
+    fn images_over_the_threshold_are_flagged() {
+        let default = classifier(0.5);
+        assert!(flagged(&default, &[0.02, 0.0, 0.0]));
+        assert!(!flagged(&default, &[0.0, 0.0, 0.02]));
+        // a third for each prompt, two thirds are unsafe
+        assert!(flagged(&default, &[0.0, 0.0, 0.0]));
+        assert!(!flagged(&classifier(0.7), &[0.0, 0.0, 0.0]));
+    }
+}
@@ -0,0 +1,176 @@
+use anyhow::Result;
+use confique::Config;
+use qdrant_client::Payload;
+use serde::Serialize;
+
+use xlib::client::ClipClient;
+
+use crate::{
+    config,
+    zero_shot::{self, Annotator, LabelSet},
+};
+
+pub const TAGS_FIELD: &str = "tags";
+pub const TAGS_VERSION_FIELD: &str = "tags_version";
+
+#[derive(Config)]
+pub struct TaggingConfig {
+    // `TAG_LABELS` is a comma separated list of labels, set it to an empty string to disable
+    // auto-tagging
+    #[config(
+        default = [
+            "animal",
+            "architecture",
+            "beach",
+            "bird",
+            "boat",
+            "car",
+            "cat",
+            "city",
+            "document",
+            "dog",
+            "flower",
+            "food",
+            "forest",
+            "indoor",
+            "mountain",
+            "night",
+            "people",
+            "portrait",
+            "road",
+            "snow",
+            "sport",
+            "sunset",
+            "text",
+            "tree",
+            "water",
+        ],
+        env = "TAG_LABELS",
+        parse_env = config::list_by_comma
+    )]
+    pub labels: Vec<String>,
+    // `{}` is replaced by the label
+    #[config(default = "a photo of {}", env = "TAG_PROMPT_TEMPLATE")]
+    pub prompt_template: String,
+    // Maximum number of tags stored per image
+    #[config(default = 3, env = "TAG_TOP_K")]
+    pub top_k: usize,
+    #[config(default = 0.1, env = "TAG_MIN_CONFIDENCE")]
+    pub min_confidence: f32,
+}
+
+#[derive(Serialize)]
+pub struct Tag {
+    pub label: String,
+    pub confidence: f32,
+}
+
+pub struct Tagger {
+    label_set: LabelSet,
+    top_k: usize,
+    min_confidence: f32,
+    // Identifies the vocabulary the tags were computed with, images tagged with another
+    // version are re-tagged when the worker starts
+    version: String,
+}
+
+impl Tagger {
+    pub async fn new(clip_client: &ClipClient, config: &TaggingConfig) -> Result<Self> {
+        let version = zero_shot::version(&[
+            &config.prompt_template,
+            &config.top_k.to_string(),
+            &config.min_confidence.to_string(),
+            &config.labels.join(","),
+        ]);
+        let label_set =
+            LabelSet::embed(clip_client, config.labels.clone(), &config.prompt_template).await?;
+
+        Ok(Self {
+            label_set,
+            top_k: config.top_k,
+            min_confidence: config.min_confidence,
+            version,
+        })
+    }
+
+    pub fn tag(&self, image_vector: &[f32]) -> Vec<Tag> {
+        let mut tags = self
+            .label_set
+            .labels
+            .iter()
+            .zip(self.label_set.probabilities(image_vector))
+            .filter(|(_, confidence)| *confidence >= self.min_confidence)
+            .map(|(label, confidence)| Tag {
+                label: label.clone(),
+                confidence,
+            })
+            .collect::<Vec<_>>();
+        tags.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        tags.truncate(self.top_k);
+        tags
+    }
+}
+
+impl Annotator for Tagger {
+    fn name(&self) -> &'static str {
+        "tags"
+    }
+
+    fn version_field(&self) -> &'static str {
+        TAGS_VERSION_FIELD
+    }
+
+    fn version(&self) -> &str {
+        &self.version
+    }
+
+    fn annotate(&self, payload: &mut Payload, image_vector: &[f32]) {
+        payload.insert(
+            TAGS_FIELD,
+            serde_json::to_value(self.tag(image_vector)).unwrap_or_default(),
+        );
+        payload.insert(TAGS_VERSION_FIELD, self.version.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tagger(top_k: usize, min_confidence: f32) -> Tagger {
+        Tagger {
+            label_set: LabelSet::from_vectors(
+                &["beach", "dog", "city"],
+                vec![
+                    vec![1.0, 0.0, 0.0],
+                    vec![0.0, 1.0, 0.0],
+                    vec![0.0, 0.0, 1.0],
+                ],
+            ),
+            top_k,
+            min_confidence,
+            version: String::new(),
+        }
+    }
+
+    fn labels(tags: &[Tag]) -> Vec<&str> {
+        tags.iter().map(|tag| tag.label.as_str()).collect()
+    }
+
+    // probabilities of about 0.245 for beach, 0.665 for dog and 0.090 for city
+    const IMAGE: [f32; 3] = [0.01, 0.02, 0.0];
+
+    #[test]
+    fn tag_keeps_the_top_k_most_confident() {
+        let tags = tagger(2, 0.0).tag(&IMAGE);
+        assert_eq!(labels(&tags), ["dog", "beach"]);
+        assert!(tags[0].confidence > tags[1].confidence);
+    }
+
+    #[test]
+    fn tag_drops_the_labels_under_min_confidence() {
+        assert_eq!(labels(&tagger(3, 0.1).tag(&IMAGE)), ["dog", "beach"]);
+        assert_eq!(labels(&tagger(3, 0.5).tag(&IMAGE)), ["dog"]);
+        assert!(tagger(3, 0.9).tag(&IMAGE).is_empty());
+    }
+}
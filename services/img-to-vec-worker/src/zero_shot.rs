@@ -0,0 +1,179 @@
+use anyhow::{Context, Result};
+use qdrant_client::{
+    qdrant::{Condition, Filter, ScrollPointsBuilder, SetPayloadPointsBuilder},
+    Payload, Qdrant,
+};
+use uuid::Uuid;
+
+use xlib::{client::ClipClient, collection::dense_vector};
+
+// CLIP's learned temperature, turns cosine similarities into softmax logits
+const LOGIT_SCALE: f32 = 100.0;
+const REANNOTATE_BATCH_SIZE: u32 = 100;
+
+// Text prompts embedded once by the CLIP model, image vectors are then classified against
+// them without any training
+pub struct LabelSet {
+    pub labels: Vec<String>,
+    vectors: Vec<Vec<f32>>,
+}
+
+impl LabelSet {
+    // `prompt_template` wraps each label, `{}` is replaced by the label, e.g. "a photo of {}"
+    pub async fn embed(
+        clip_client: &ClipClient,
+        labels: Vec<String>,
+        prompt_template: &str,
+    ) -> Result<Self> {
+        let prompts = labels
+            .iter()
+            .map(|label| prompt_template.replace("{}", label))
+            .collect::<Vec<_>>();
+        let vectors = clip_client
+            .texts_to_vectors(&prompts)
+            .await
+            .with_context(|| format!("failed to embed the label prompts `{prompt_template}`"))?;
+
+        Ok(Self { labels, vectors })
+    }
+
+    // Label vectors given as is, without the CLIP model
+    #[cfg(test)]
+    pub fn from_vectors(labels: &[&str], vectors: Vec<Vec<f32>>) -> Self {
+        Self {
+            labels: labels.iter().map(ToString::to_string).collect(),
+            vectors,
+        }
+    }
+
+    // Probability of each label for the image, in the order of `labels`. The vectors returned
+    // by the CLIP model are normalized so the dot product is the cosine similarity.
+    pub fn probabilities(&self, image_vector: &[f32]) -> Vec<f32> {
+        let logits = self
+            .vectors
+            .iter()
+            .map(|v| LOGIT_SCALE * v.iter().zip(image_vector).map(|(a, b)| a * b).sum::<f32>())
+            .collect::<Vec<_>>();
+
+        let max_logit = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exps = logits
+            .iter()
+            .map(|l| (l - max_logit).exp())
+            .collect::<Vec<_>>();
+        let sum = exps.iter().sum::<f32>();
+
+        exps.into_iter().map(|e| e / sum).collect()
+    }
+}
+
+// Payload computed from the image vector alone, so it can be recomputed from the stored
+// vectors whenever its configuration changes
+pub trait Annotator {
+    fn name(&self) -> &'static str;
+    // Payload field holding the version of the configuration the payload was computed with
+    fn version_field(&self) -> &'static str;
+    fn version(&self) -> &str;
+    fn annotate(&self, payload: &mut Payload, image_vector: &[f32]);
+}
+
+// Stable identifier of a configuration
+pub fn version(parts: &[&str]) -> String {
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, parts.join("\n").as_bytes()).to_string()
+}
+
+// Recompute the payload of the images annotated with another configuration version
+pub async fn reannotate_outdated_points(
+    qdrant_client: &Qdrant,
+    collection_name: &str,
+    annotator: &dyn Annotator,
+) -> Result<usize> {
+    let mut reannotated = 0;
+    let mut offset = None;
+    loop {
+        let mut scroll = ScrollPointsBuilder::new(collection_name)
+            .filter(Filter::must_not([Condition::matches(
+                annotator.version_field(),
+                annotator.version().to_string(),
+            )]))
+            .limit(REANNOTATE_BATCH_SIZE)
+            .with_payload(false)
+            .with_vectors(true);
+        if let Some(offset) = offset.take() {
+            scroll = scroll.offset(offset);
+        }
+        let response = qdrant_client
+            .scroll(scroll)
+            .await
+            .context("failed to scroll outdated points")?;
+
+        for point in response.result {
+            let (Some(id), Some(vector)) = (point.id, point.vectors.and_then(dense_vector)) else {
+                continue;
+            };
+            let mut payload = Payload::new();
+            annotator.annotate(&mut payload, &vector);
+            qdrant_client
+                .set_payload(
+                    SetPayloadPointsBuilder::new(collection_name, payload)
+                        .points_selector(vec![id])
+                        .wait(true),
+                )
+                .await
+                .with_context(|| format!("failed to update {}", annotator.name()))?;
+            reannotated += 1;
+        }
+
+        match response.next_page_offset {
+            Some(next_page_offset) => offset = Some(next_page_offset),
+            None => break,
+        }
+    }
+
+    Ok(reannotated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn label_set() -> LabelSet {
+        LabelSet::from_vectors(
+            &["beach", "dog", "city"],
+            vec![
+                vec![1.0, 0.0, 0.0],
+                vec![0.0, 1.0, 0.0],
+                vec![0.0, 0.0, 1.0],
+            ],
+        )
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-3, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn probabilities_are_a_softmax_of_the_scaled_similarities() {
+        // logits of 2, 1 and 0
+        let probabilities = label_set().probabilities(&[0.02, 0.01, 0.0]);
+        assert_close(&probabilities, &[0.665, 0.245, 0.090]);
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn probabilities_are_uniform_without_similarity() {
+        let third = 1.0 / 3.0;
+        assert_close(
+            &label_set().probabilities(&[0.0, 0.0, 0.0]),
+            &[third, third, third],
+        );
+    }
+
+    #[test]
+    fn probabilities_dont_overflow_on_large_similarities() {
+        let probabilities = label_set().probabilities(&[10.0, 0.0, 0.0]);
+        assert_close(&probabilities, &[1.0, 0.0, 0.0]);
+    }
+}
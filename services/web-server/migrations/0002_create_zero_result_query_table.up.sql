@@ -0,0 +1,7 @@
+CREATE TABLE zero_result_query (
+    id SERIAL PRIMARY KEY,
+    text TEXT NOT NULL,
+    min_score REAL NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX zero_result_query_created_at_idx ON zero_result_query (created_at);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE feedback ALTER COLUMN image_name TYPE VARCHAR(255);
@@ -0,0 +1,2 @@
+-- image names are paths relative to the images root, they can be longer than 255 characters
+ALTER TABLE feedback ALTER COLUMN image_name TYPE TEXT;
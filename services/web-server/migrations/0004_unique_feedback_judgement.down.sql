@@ -0,0 +1,2 @@
+DROP INDEX feedback_judgement_token_idx;
+ALTER TABLE feedback DROP COLUMN judgement_token;
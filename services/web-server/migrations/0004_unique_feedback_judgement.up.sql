@@ -0,0 +1,6 @@
+-- Token of the search match a refine-search judgement was made on, NULL for the feedback of
+-- create-feedback. A refinement judges the same matches again, each is recorded once.
+ALTER TABLE feedback ADD COLUMN judgement_token TEXT NULL;
+-- The token is hashed, it carries the query and can be longer than an index entry allows
+CREATE UNIQUE INDEX feedback_judgement_token_idx ON feedback (md5(judgement_token))
+WHERE judgement_token IS NOT NULL;
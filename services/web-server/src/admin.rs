@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    routing::{delete, get, post},
+    Json, Router,
+};
+use qdrant_client::qdrant::{CollectionStatus, PayloadSchemaType};
+use serde::{Deserialize, Serialize};
+use xlib::{app::metrics::observe, collection::create_payload_indexes};
+
+use crate::{
+    error::ApiError,
+    extract::{ApiJson, ApiPath, ApiQuery},
+    repo::{self, Feedback},
+    AppState,
+};
+
+const DEFAULT_FEEDBACK_PAGE_SIZE: i64 = 50;
+const MAX_FEEDBACK_PAGE_SIZE: i64 = 500;
+
+// Operational endpoints, served on the private port only
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/admin/v1/collection", get(collection_stats_handler))
+        .route("/admin/v1/collection/reindex", post(reindex_handler))
+        .route("/admin/v1/feedback", get(list_feedback_handler))
+        .route("/admin/v1/feedback/{id}", delete(delete_feedback_handler))
+        .route("/admin/v1/config", get(config_handler))
+        .route(
+            "/admin/v1/embedding-cache/stats",
+            get(|State(state): State<AppState>| async move { Json(state.embedding_cache.stats()) }),
+        )
+        .route(
+            "/admin/v1/log-level",
+            get(log_level_handler).put(set_log_level_handler),
+        )
+}
+
+#[derive(Serialize)]
+struct CollectionStats {
+    name: String,
+    status: &'static str,
+    points_count: Option<u64>,
+    indexed_vectors_count: Option<u64>,
+    segments_count: u64,
+    // Error of the last optimization, if any
+    optimizer_error: Option<String>,
+    payload_indexes: BTreeMap<String, PayloadIndexStats>,
+}
+
+#[derive(Serialize)]
+struct PayloadIndexStats {
+    data_type: &'static str,
+    points: Option<u64>,
+}
+
+async fn collection_stats_handler(
+    State(state): State<AppState>,
+) -> Result<Json<CollectionStats>, ApiError> {
+    let name = state.config.qdrant.collection.clone();
+    let info = observe(
+        "qdrant",
+        "collection_info",
+        state.qdrant_client.collection_info(name.as_str()),
+    )
+    .await?
+    .result
+    .ok_or_else(|| ApiError::Internal(format!("Qdrant returned no info on {name}")))?;
+
+    Ok(Json(CollectionStats {
+        name,
+        status: CollectionStatus::try_from(info.status)
+            .unwrap_or_default()
+            .as_str_name(),
+        points_count: info.points_count,
+        indexed_vectors_count: info.indexed_vectors_count,
+        segments_count: info.segments_count,
+        optimizer_error: info
+            .optimizer_status
+            .filter(|status| !status.ok)
+            .map(|status| status.error),
+        payload_indexes: info
+            .payload_schema
+            .into_iter()
+            .map(|(field, schema)| {
+                let stats = PayloadIndexStats {
+                    data_type: PayloadSchemaType::try_from(schema.data_type)
+                        .unwrap_or_default()
+                        .as_str_name(),
+                    points: schema.points,
+                };
+                (field, stats)
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Serialize)]
+struct ReindexResponse {
+    indexed: Vec<&'static str>,
+    failed: Vec<ReindexFailure>,
+}
+
+#[derive(Serialize)]
+struct ReindexFailure {
+    field: &'static str,
+    error: String,
+}
+
+// Creates the payload indexes, e.g. after the collection was restored from a snapshot
+async fn reindex_handler(State(state): State<AppState>) -> Json<ReindexResponse> {
+    let mut response = ReindexResponse {
+        indexed: Vec::new(),
+        failed: Vec::new(),
+    };
+    let outcomes =
+        create_payload_indexes(&state.qdrant_client, &state.config.qdrant.collection).await;
+    for (field, result) in outcomes {
+        match result {
+            Ok(()) => response.indexed.push(field),
+            Err(e) => {
+                tracing::warn!("Failed to create payload index on {}: {}", field, e);
+                response.failed.push(ReindexFailure {
+                    field,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    Json(response)
+}
+
+#[derive(Deserialize)]
+struct ListFeedbackQuery {
+    image_name: Option<String>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+async fn list_feedback_handler(
+    State(state): State<AppState>,
+    ApiQuery(query): ApiQuery<ListFeedbackQuery>,
+) -> Result<Json<Vec<Feedback>>, ApiError> {
+    let repo = repo::Repo::new(state.pg_client);
+    let feedback = repo
+        .list_feedback(
+            query.image_name,
+            query
+                .limit
+                .unwrap_or(DEFAULT_FEEDBACK_PAGE_SIZE)
+                .clamp(1, MAX_FEEDBACK_PAGE_SIZE),
+            query.offset.unwrap_or(0).max(0),
+        )
+        .await
+        .map_err(ApiError::Database)?;
+    Ok(Json(feedback))
+}
+
+// Soft delete, e.g. for spam. The feedback is kept in the table with `deleted_at` set.
+async fn delete_feedback_handler(
+    State(state): State<AppState>,
+    ApiPath(id): ApiPath<i32>,
+) -> Result<StatusCode, ApiError> {
+    let repo = repo::Repo::new(state.pg_client);
+    if repo.delete_feedback(id).await.map_err(ApiError::Database)? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(ApiError::FeedbackNotFound(id))
+    }
+}
+
+async fn config_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::to_value(state.config.as_ref()).unwrap_or_default())
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogLevel {
+    // Syntax of `RUST_LOG`, e.g. `debug,hyper=off`
+    filter: String,
+}
+
+async fn log_level_handler(State(state): State<AppState>) -> Result<Json<LogLevel>, ApiError> {
+    let filter = state
+        .log_filter
+        .current()
+        .map_err(|e| ApiError::Internal(format!("{e:#}")))?;
+    Ok(Json(LogLevel { filter }))
+}
+
+// Lasts until the next restart, `RUST_LOG` sets the filter the service starts with
+async fn set_log_level_handler(
+    State(state): State<AppState>,
+    ApiJson(payload): ApiJson<LogLevel>,
+) -> Result<Json<LogLevel>, ApiError> {
+    state
+        .log_filter
+        .set(&payload.filter)
+        .map_err(|e| ApiError::InvalidLogFilter(format!("{e:#}")))?;
+    tracing::info!("Log filter set to {}", payload.filter);
+    log_level_handler(State(state)).await
+}
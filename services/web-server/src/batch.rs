@@ -0,0 +1,284 @@
+use axum::extract::{Json, State};
+use futures::{stream, StreamExt};
+use qdrant_client::qdrant::{
+    Filter, QueryBatchPointsBuilder, QueryPoints, QueryPointsBuilder, ScoredPoint,
+};
+use serde::{Deserialize, Serialize};
+use xlib::app::metrics::observe;
+
+use crate::{
+    error::ApiError,
+    extract::ApiJson,
+    filter::SearchFilter,
+    query::QueryError,
+    search::{self, SearchImageResponse, DEFAULT_SEARCH_LIMIT, MAX_SEARCH_LIMIT},
+    AppState,
+};
+
+const MAX_BATCH_QUERIES: usize = 1000;
+// Queries sent to Qdrant at once when a batch query failed and they are run one by one
+const FALLBACK_CONCURRENCY: usize = 16;
+
+#[derive(Deserialize)]
+pub struct BatchSearchRequest {
+    // Parsed one by one, a malformed query fails alone
+    queries: Vec<serde_json::Value>,
+}
+
+// A text query of a batch, with the same meaning as the fields of a single search
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchQuery {
+    text: String,
+    limit: Option<u64>,
+    filter: Option<SearchFilter>,
+    safe_search: Option<bool>,
+    min_score: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct BatchSearchResponse {
+    // One result per query, in the order of the queries
+    results: Vec<BatchSearchResult>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum BatchSearchResult {
+    Matches(SearchImageResponse),
+    // `text` is `null` when the query is not even an object with a text
+    Error {
+        text: Option<String>,
+        error: BatchQueryError,
+    },
+}
+
+// Same `code` and `detail` as the problem of a single search
+#[derive(Serialize)]
+struct BatchQueryError {
+    code: &'static str,
+    detail: String,
+}
+
+impl From<ApiError> for BatchQueryError {
+    fn from(e: ApiError) -> Self {
+        let status = e.status();
+        if status.is_server_error() {
+            tracing::error!(code = e.code(), "Batch query failed: {:#}", e);
+        }
+        Self {
+            code: e.code(),
+            detail: e.detail(status),
+        }
+    }
+}
+
+// A query that passed validation, waiting for its vector
+struct ValidQuery {
+    text: String,
+    limit: u64,
+    filter: Option<Filter>,
+    min_score: Option<f32>,
+}
+
+type QueryResult<T> = Result<T, (Option<String>, BatchQueryError)>;
+
+// Many text queries in one call: the texts are embedded by batches and the searches are sent
+// to Qdrant as a single batch query. A query that is invalid or fails in Qdrant fails alone,
+// the others still get their matches.
+pub async fn batch_search_image_handler(
+    State(state): State<AppState>,
+    ApiJson(payload): ApiJson<BatchSearchRequest>,
+) -> Result<Json<BatchSearchResponse>, ApiError> {
+    if payload.queries.len() > MAX_BATCH_QUERIES {
+        return Err(ApiError::BatchTooLarge(MAX_BATCH_QUERIES));
+    }
+
+    let queries = payload
+        .queries
+        .into_iter()
+        .map(|query| validate(query, state.config.search.min_score))
+        .collect::<Vec<_>>();
+    let valid_queries = queries.iter().filter_map(|q| q.as_ref().ok());
+
+    let texts = valid_queries
+        .clone()
+        .map(|q| q.text.clone())
+        .collect::<Vec<_>>();
+    let vectors = state.embedding_cache.texts_to_vectors(&texts).await?;
+
+    let collection_name = state.config.qdrant.collection.as_str();
+    let query_points = valid_queries
+        .zip(vectors)
+        .map(|(query, vector)| to_query_points(collection_name, query, vector))
+        .collect::<Vec<_>>();
+    let mut batch_results = run_queries(&state, query_points).await.into_iter();
+
+    let mut results = Vec::with_capacity(queries.len());
+    let mut zero_results = Vec::new();
+    for query in queries {
+        let result = query.and_then(|query| {
+            let points = batch_results
+                .next()
+                .unwrap_or_else(|| Ok(Vec::new()))
+                .map_err(|e| (Some(query.text.clone()), e.into()))?;
+            let response = search::build_response(query.text.clone(), points)
+                .map_err(|e| (Some(query.text.clone()), e.into()))?;
+            if response.matches.is_empty() {
+                zero_results.push((query.text, query.min_score));
+            }
+            Ok(response)
+        });
+        results.push(match result {
+            Ok(response) => BatchSearchResult::Matches(response),
+            Err((text, error)) => BatchSearchResult::Error { text, error },
+        });
+    }
+
+    // recorded in the background, in one statement
+    if !zero_results.is_empty() {
+        tokio::spawn(search::record_zero_results(state, zero_results));
+    }
+    Ok(Json(BatchSearchResponse { results }))
+}
+
+// One batch query to Qdrant. When it fails, e.g. over one query Qdrant rejects, the queries are
+// sent one by one so that only the failing ones fail.
+async fn run_queries(
+    state: &AppState,
+    query_points: Vec<QueryPoints>,
+) -> Vec<Result<Vec<ScoredPoint>, ApiError>> {
+    if query_points.is_empty() {
+        return Vec::new();
+    }
+
+    let collection_name = state.config.qdrant.collection.as_str();
+    let batch = QueryBatchPointsBuilder::new(collection_name, query_points.clone());
+    match observe(
+        "qdrant",
+        "query_batch",
+        state.qdrant_client.query_batch(batch),
+    )
+    .await
+    {
+        Ok(response) => response
+            .result
+            .into_iter()
+            .map(|batch_result| Ok(batch_result.result))
+            .collect(),
+        Err(e) => {
+            tracing::warn!("Batch query failed, running the queries one by one: {}", e);
+            stream::iter(query_points)
+                .map(|query| async {
+                    observe("qdrant", "query", state.qdrant_client.query(query))
+                        .await
+                        .map(|response| response.result)
+                        .map_err(ApiError::from)
+                })
+                .buffered(FALLBACK_CONCURRENCY)
+                .collect()
+                .await
+        }
+    }
+}
+
+// The text of the query is kept along with the error so that the caller can match them up
+fn validate(query: serde_json::Value, default_min_score: Option<f32>) -> QueryResult<ValidQuery> {
+    let text = query
+        .get("text")
+        .and_then(serde_json::Value::as_str)
+        .map(str::to_string);
+    let query = serde_json::from_value::<BatchQuery>(query)
+        .map_err(|e| (text, ApiError::MalformedQuery(e).into()))?;
+    let invalid = |text: String, e: ApiError| (Some(text), BatchQueryError::from(e));
+
+    if query.text.trim().is_empty() {
+        return Err(invalid(query.text, QueryError::Empty.into()));
+    }
+    let min_score = query.min_score.or(default_min_score);
+    if min_score.is_some_and(|score| !score.is_finite()) {
+        return Err(invalid(query.text, QueryError::InvalidMinScore.into()));
+    }
+    let filter = query
+        .filter
+        .unwrap_or_default()
+        .to_qdrant_filter(query.safe_search.unwrap_or(true));
+    match filter {
+        Ok(filter) => Ok(ValidQuery {
+            text: query.text,
+            limit: query
+                .limit
+                .unwrap_or(DEFAULT_SEARCH_LIMIT)
+                .clamp(1, MAX_SEARCH_LIMIT),
+            filter,
+            min_score,
+        }),
+        Err(e) => Err(invalid(query.text, e.into())),
+    }
+}
+
+fn to_query_points(collection_name: &str, query: &ValidQuery, vector: Vec<f32>) -> QueryPoints {
+    let mut query_points = QueryPointsBuilder::new(collection_name)
+        .query(vector)
+        .limit(query.limit)
+        .with_payload(true);
+    if let Some(filter) = query.filter.clone() {
+        query_points = query_points.filter(filter);
+    }
+    if let Some(min_score) = query.min_score {
+        query_points = query_points.score_threshold(min_score);
+    }
+    query_points.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(result: QueryResult<ValidQuery>) -> (Option<String>, &'static str) {
+        let Err((text, error)) = result else {
+            panic!("the query is valid");
+        };
+        (text, error.code)
+    }
+
+    #[test]
+    fn valid_query() {
+        let Ok(query) = validate(serde_json::json!({"text": "beach", "limit": 500}), None) else {
+            panic!("the query is invalid");
+        };
+        assert_eq!(query.text, "beach");
+        assert_eq!(query.limit, MAX_SEARCH_LIMIT);
+    }
+
+    #[test]
+    fn malformed_query_fails_alone() {
+        assert_eq!(
+            error(validate(
+                serde_json::json!({"text": "beach", "colour": "red"}),
+                None
+            )),
+            (Some("beach".to_string()), "invalid_query")
+        );
+        assert_eq!(
+            error(validate(serde_json::json!("beach"), None)),
+            (None, "invalid_query")
+        );
+    }
+
+    #[test]
+    fn invalid_query_has_a_stable_code() {
+        assert_eq!(
+            error(validate(serde_json::json!({"text": " "}), None)),
+            (Some(" ".to_string()), "invalid_query")
+        );
+        let radius = serde_json::json!({"text": "beach", "filter": {"geo": {"radius": {
+            "center": {"lat": 25.0, "lon": 121.0},
+            "meters": 0.0,
+        }}}});
+        assert_eq!(
+            error(validate(radius, None)),
+            (Some("beach".to_string()), "invalid_filter")
+        );
+    }
+}
@@ -0,0 +1,245 @@
+use std::{
+    net::{Ipv4Addr, SocketAddrV4},
+    time::Duration,
+};
+
+use confique::Config;
+use serde::{Serialize, Serializer};
+
+use crate::embedding_cache::EmbeddingCacheConfig;
+
+// Read next to the binary, `CONFIG_FILE` points to another file
+const DEFAULT_CONFIG_FILE: &str = "config.yaml";
+
+// Configuration of the web server, loaded from `config.yaml`. Environment variables override
+// the values of the file, and every value not in either has a default unless noted otherwise.
+// Serialized for the admin API, with the secrets redacted.
+#[derive(Config, Serialize)]
+pub struct AppConfig {
+    #[config(nested)]
+    pub public_http: HttpConfig,
+    // Admin API, keep it off the public network
+    #[config(nested)]
+    pub private_http: HttpConfig,
+    #[config(nested)]
+    pub database: DatabaseConfig,
+    #[config(nested)]
+    pub redis: RedisConfig,
+    #[config(nested)]
+    pub qdrant: QdrantConfig,
+    #[config(nested)]
+    pub clip: ClipConfig,
+    #[config(nested)]
+    pub embedding_cache: EmbeddingCacheConfig,
+    #[config(nested)]
+    pub search: SearchConfig,
+    #[config(nested)]
+    pub startup: StartupConfig,
+    #[config(nested)]
+    pub health: HealthConfig,
+}
+
+impl AppConfig {
+    pub fn load() -> Result<Self, confique::Error> {
+        let file = std::env::var("CONFIG_FILE").unwrap_or_else(|_| DEFAULT_CONFIG_FILE.to_string());
+        Self::builder().env().file(file).load()
+    }
+}
+
+#[derive(Config, Serialize)]
+pub struct HttpConfig {
+    #[config(default = "0.0.0.0")]
+    pub addr: Ipv4Addr,
+    // required
+    pub port: u16,
+}
+
+impl HttpConfig {
+    pub const fn socket_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.addr, self.port)
+    }
+}
+
+#[derive(Config, Serialize)]
+pub struct DatabaseConfig {
+    // required
+    #[config(env = "DATABASE_HOSTNAME")]
+    pub hostname: String,
+    #[config(env = "DATABASE_PORT")]
+    pub port: Option<u16>,
+    #[config(env = "DATABASE_USER")]
+    pub user: Option<String>,
+    #[config(env = "DATABASE_PASSWORD")]
+    #[serde(serialize_with = "redact")]
+    pub password: Option<String>,
+    #[config(default = "web-server", env = "DATABASE_NAME")]
+    pub name: String,
+    #[config(default = 5, validate(*max_connections > 0, "must be positive"))]
+    pub max_connections: u32,
+    // How long a query waits for a free connection of the pool
+    #[config(default = 30, validate(*acquire_timeout_secs > 0, "must be positive"))]
+    pub acquire_timeout_secs: u64,
+}
+
+impl DatabaseConfig {
+    pub const fn acquire_timeout(&self) -> Duration {
+        Duration::from_secs(self.acquire_timeout_secs)
+    }
+}
+
+// The embedding cache works without Redis, it then only keeps the vectors in process
+#[derive(Config, Serialize)]
+pub struct RedisConfig {
+    #[config(env = "REDIS_HOSTNAME")]
+    pub hostname: Option<String>,
+    #[config(env = "REDIS_PORT")]
+    pub port: Option<u16>,
+    #[config(env = "REDIS_PASSWORD")]
+    #[serde(serialize_with = "redact")]
+    pub password: Option<String>,
+    // Also the connect timeout. A slow Redis costs no more than this, the cache is then skipped.
+    #[config(
+        default = 200,
+        env = "REDIS_TIMEOUT_MS",
+        validate(*timeout_ms > 0, "must be positive")
+    )]
+    pub timeout_ms: u64,
+}
+
+impl RedisConfig {
+    pub const fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+#[derive(Config, Serialize)]
+pub struct QdrantConfig {
+    #[config(
+        default = "http://qdrant:6334",
+        env = "QDRANT_URL",
+        validate = not_empty
+    )]
+    pub url: String,
+    // Collection the img-to-vec worker fills
+    #[config(
+        default = "clip_images_collection",
+        env = "QDRANT_COLLECTION",
+        validate = not_empty
+    )]
+    pub collection: String,
+    // Size of the vectors of the CLIP model, checked against the collection by the readiness probe
+    #[config(default = 512, validate(*vector_size > 0, "must be positive"))]
+    pub vector_size: u64,
+    #[config(default = 10, validate(*timeout_secs > 0, "must be positive"))]
+    pub timeout_secs: u64,
+    #[config(default = 5, validate(*connect_timeout_secs > 0, "must be positive"))]
+    pub connect_timeout_secs: u64,
+}
+
+impl QdrantConfig {
+    pub const fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub const fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+}
+
+#[derive(Config, Serialize)]
+pub struct ClipConfig {
+    #[config(
+        default = "http://clip-model:8000",
+        env = "CLIP_MODEL_URL",
+        validate = not_empty
+    )]
+    pub url: String,
+    #[config(default = 30, validate(*timeout_secs > 0, "must be positive"))]
+    pub timeout_secs: u64,
+    #[config(default = 5, validate(*connect_timeout_secs > 0, "must be positive"))]
+    pub connect_timeout_secs: u64,
+    // Idle connections kept open to the CLIP model
+    #[config(default = 32)]
+    pub pool_max_idle_per_host: usize,
+    // Texts sent to the CLIP model in one request, bounds the memory of a batch on the model side
+    #[config(default = 64, validate(*batch_size > 0, "must be positive"))]
+    pub batch_size: usize,
+}
+
+impl ClipConfig {
+    pub const fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    pub const fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout_secs)
+    }
+}
+
+#[derive(Config, Serialize)]
+pub struct SearchConfig {
+    // Minimum cosine similarity of the matches when the request doesn't set one
+    #[config(
+        env = "SEARCH_MIN_SCORE",
+        validate(min_score.is_finite(), "must be a finite number")
+    )]
+    pub min_score: Option<f32>,
+    // Largest uploaded reference image, decoded. It also bounds the size of the request bodies.
+    #[config(
+        default = 10_485_760,
+        env = "SEARCH_MAX_IMAGE_BYTES",
+        validate(*max_image_bytes > 0, "must be positive")
+    )]
+    pub max_image_bytes: usize,
+}
+
+// Room for the rest of a request along with the image
+const REQUEST_OVERHEAD_BYTES: usize = 64 * 1024;
+
+impl SearchConfig {
+    // The image is uploaded base64 encoded, 4 bytes for every 3
+    pub const fn max_body_bytes(&self) -> usize {
+        self.max_image_bytes.div_ceil(3) * 4 + REQUEST_OVERHEAD_BYTES
+    }
+}
+
+// The dependencies may still be starting up along with the web server
+#[derive(Config, Serialize)]
+pub struct StartupConfig {
+    #[config(default = 30, validate(*health_check_attempts > 0, "must be positive"))]
+    pub health_check_attempts: u32,
+    #[config(default = 2)]
+    pub health_check_interval_secs: u64,
+}
+
+impl StartupConfig {
+    pub const fn health_check_interval(&self) -> Duration {
+        Duration::from_secs(self.health_check_interval_secs)
+    }
+}
+
+#[derive(Config, Serialize)]
+pub struct HealthConfig {
+    // How long the readiness probe waits for each dependency
+    #[config(default = 2, validate(*probe_timeout_secs > 0, "must be positive"))]
+    pub probe_timeout_secs: u64,
+}
+
+impl HealthConfig {
+    pub const fn probe_timeout(&self) -> Duration {
+        Duration::from_secs(self.probe_timeout_secs)
+    }
+}
+
+pub fn not_empty(value: &impl AsRef<str>) -> Result<(), &'static str> {
+    if value.as_ref().is_empty() {
+        return Err("must not be empty");
+    }
+    Ok(())
+}
+
+// serde passes the field by reference
+#[allow(clippy::ref_option)]
+fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| "<redacted>").serialize(serializer)
+}
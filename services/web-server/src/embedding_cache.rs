@@ -0,0 +1,222 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+use confique::Config;
+use lru::LruCache;
+use serde::Serialize;
+use xlib::client::{ClipClient, ClipError, RedisClient};
+
+#[derive(Config, Serialize)]
+pub struct EmbeddingCacheConfig {
+    // Part of the cache key, change it when the CLIP model changes
+    #[config(default = "openai/clip-vit-base-patch32", env = "CLIP_MODEL_VERSION")]
+    pub model_version: String,
+    // Number of vectors kept in process
+    #[config(default = 10_000, env = "EMBEDDING_CACHE_LRU_CAPACITY")]
+    pub lru_capacity: NonZeroUsize,
+    // 7 days
+    #[config(default = 604_800, env = "EMBEDDING_CACHE_TTL_SECONDS")]
+    pub ttl_seconds: u64,
+}
+
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub lru_hits: u64,
+    pub redis_hits: u64,
+    pub misses: u64,
+    pub hit_rate: f64,
+}
+
+// Text vectors keyed by normalized query text and model version. An in-process LRU sits in
+// front of Redis, and Redis is shared by all the web server instances. Redis is optional and
+// its failures only cost a call to the CLIP model.
+pub struct EmbeddingCache {
+    lru: Mutex<LruCache<String, Vec<f32>>>,
+    // Empty until Redis is connected, for good without a Redis hostname
+    redis: Arc<OnceLock<RedisClient>>,
+    clip_client: ClipClient,
+    model_version: String,
+    ttl_seconds: u64,
+    lru_hits: AtomicU64,
+    redis_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl EmbeddingCache {
+    pub fn new(
+        config: &EmbeddingCacheConfig,
+        clip_client: ClipClient,
+        redis: Arc<OnceLock<RedisClient>>,
+    ) -> Self {
+        Self {
+            lru: Mutex::new(LruCache::new(config.lru_capacity)),
+            redis,
+            clip_client,
+            model_version: config.model_version.clone(),
+            ttl_seconds: config.ttl_seconds,
+            lru_hits: AtomicU64::new(0),
+            redis_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    // Vectors of the texts in the same order, the misses are embedded as one batch
+    #[tracing::instrument(name = "embed_queries", skip_all, fields(count = texts.len()))]
+    pub async fn texts_to_vectors(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, ClipError> {
+        let keys = texts.iter().map(|t| self.key(t)).collect::<Vec<_>>();
+        let mut vectors = {
+            let mut lru = self.lru.lock().unwrap();
+            keys.iter()
+                .map(|key| lru.get(key).cloned())
+                .collect::<Vec<_>>()
+        };
+        for _ in vectors.iter().flatten() {
+            self.record_lookup(&self.lru_hits, "lru_hit");
+        }
+
+        let not_in_lru = missing(&vectors);
+        let cached = self
+            .redis_get(not_in_lru.iter().map(|&i| keys[i].as_str()).collect())
+            .await;
+        for (i, vector) in not_in_lru.into_iter().zip(cached) {
+            if let Some(vector) = vector {
+                self.lru
+                    .lock()
+                    .unwrap()
+                    .put(keys[i].clone(), vector.clone());
+                self.record_lookup(&self.redis_hits, "redis_hit");
+                vectors[i] = Some(vector);
+            } else {
+                self.record_lookup(&self.misses, "miss");
+            }
+        }
+
+        let not_cached = missing(&vectors);
+        if !not_cached.is_empty() {
+            let missing_texts = not_cached
+                .iter()
+                .map(|&i| texts[i].clone())
+                .collect::<Vec<_>>();
+            let embedded = self.clip_client.texts_to_vectors(&missing_texts).await?;
+            let mut entries = Vec::with_capacity(embedded.len());
+            for (i, vector) in not_cached.into_iter().zip(embedded) {
+                entries.push((keys[i].clone(), vector.clone()));
+                vectors[i] = Some(vector);
+            }
+            self.put(entries).await;
+        }
+
+        Ok(vectors.into_iter().flatten().collect())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let lru_hits = self.lru_hits.load(Ordering::Relaxed);
+        let redis_hits = self.redis_hits.load(Ordering::Relaxed);
+        let misses = self.misses.load(Ordering::Relaxed);
+        let lookups = lru_hits + redis_hits + misses;
+        #[allow(clippy::cast_precision_loss)]
+        let hit_rate = if lookups == 0 {
+            0.0
+        } else {
+            (lru_hits + redis_hits) as f64 / lookups as f64
+        };
+
+        CacheStats {
+            lru_hits,
+            redis_hits,
+            misses,
+            hit_rate,
+        }
+    }
+
+    // Case and spacing don't change the meaning of a query, e.g. `Red  Sofa` and `red sofa`
+    fn key(&self, text: &str) -> String {
+        let normalized = text
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect::<Vec<_>>()
+            .join(" ");
+        format!("embedding:{}:{}", self.model_version, normalized)
+    }
+
+    // One MGET for all the keys, a failure is the same as misses
+    async fn redis_get(&self, keys: Vec<&str>) -> Vec<Option<Vec<f32>>> {
+        let misses = vec![None; keys.len()];
+        let Some(redis) = self.redis.get() else {
+            return misses;
+        };
+        if keys.is_empty() {
+            return misses;
+        }
+
+        let mut connection = redis.clone();
+        match redis::cmd("MGET")
+            .arg(keys)
+            .query_async::<Vec<Option<Vec<u8>>>>(&mut *connection)
+            .await
+        {
+            Ok(cached) => cached
+                .into_iter()
+                .map(|bytes| bytes.map(|b| from_bytes(&b)))
+                .collect(),
+            Err(e) => {
+                tracing::warn!("Failed to read the embedding cache: {}", e);
+                misses
+            }
+        }
+    }
+
+    // `embedding_cache_lookups_total` by result, and the hit rate since the start as a gauge
+    fn record_lookup(&self, counter: &AtomicU64, result: &'static str) {
+        counter.fetch_add(1, Ordering::Relaxed);
+        metrics::counter!("embedding_cache_lookups_total", "result" => result).increment(1);
+        metrics::gauge!("embedding_cache_hit_rate").set(self.stats().hit_rate);
+    }
+
+    // One pipeline of SET EX for all the entries
+    async fn put(&self, entries: Vec<(String, Vec<f32>)>) {
+        if let Some(redis) = self.redis.get() {
+            let mut pipeline = redis::pipe();
+            for (key, vector) in &entries {
+                pipeline
+                    .set_ex(key, to_bytes(vector), self.ttl_seconds)
+                    .ignore();
+            }
+            let mut connection = redis.clone();
+            if let Err(e) = pipeline.query_async::<()>(&mut *connection).await {
+                tracing::warn!("Failed to write the embedding cache: {}", e);
+            }
+        }
+        let mut lru = self.lru.lock().unwrap();
+        for (key, vector) in entries {
+            lru.put(key, vector);
+        }
+    }
+}
+
+// Positions of the texts without a vector yet
+fn missing(vectors: &[Option<Vec<f32>>]) -> Vec<usize> {
+    vectors
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| v.is_none())
+        .map(|(i, _)| i)
+        .collect()
+}
+
+// Vectors are stored in Redis as little endian `f32`s
+fn to_bytes(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
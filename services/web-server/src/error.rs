@@ -0,0 +1,163 @@
+use axum::{
+    extract::rejection::JsonRejection,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use qdrant_client::QdrantError;
+use xlib::app::problem::Problem;
+
+use xlib::client::ClipError;
+
+use crate::{filter::FilterError, query::QueryError};
+
+// Errors of the public and admin APIs. Each one is rendered as an RFC 7807 problem with a
+// stable `code` that clients can match on, the `detail` is meant for humans and may change.
+#[derive(Debug, thiserror::Error)]
+pub enum ApiError {
+    // Malformed JSON, wrong content type or fields, too large body
+    #[error("invalid request body: {}", .0.body_text())]
+    InvalidBody(#[from] JsonRejection),
+    // Malformed query string or path
+    #[error("invalid request parameters: {0}")]
+    InvalidParameters(String),
+    #[error(transparent)]
+    InvalidQuery(#[from] QueryError),
+    #[error("invalid query: {0}")]
+    MalformedQuery(serde_json::Error),
+    #[error(transparent)]
+    InvalidFilter(#[from] FilterError),
+    #[error("image {0} is not indexed")]
+    ImageNotIndexed(String),
+    #[error("invalid image: {0}")]
+    InvalidImage(String),
+    #[error("image {0} is marked both relevant and irrelevant")]
+    ConflictingJudgement(String),
+    #[error("a batch holds at most {0} queries")]
+    BatchTooLarge(usize),
+    #[error("{0}")]
+    InvalidLogFilter(String),
+    #[error("feedback {0} does not exist")]
+    FeedbackNotFound(i32),
+    #[error("invalid feedback token: {0}")]
+    InvalidToken(#[from] jsonwebtoken::errors::Error),
+    #[error("the token of image {0} was issued for another query")]
+    MismatchedToken(String),
+    #[error(transparent)]
+    Clip(#[from] ClipError),
+    // boxed, the gRPC status makes the error large
+    #[error("Qdrant request failed: {0}")]
+    Qdrant(Box<QdrantError>),
+    #[error("database request failed: {0:#}")]
+    Database(anyhow::Error),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl From<QdrantError> for ApiError {
+    fn from(e: QdrantError) -> Self {
+        Self::Qdrant(Box::new(e))
+    }
+}
+
+impl ApiError {
+    pub const fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidBody(_) => "invalid_body",
+            Self::InvalidParameters(_) => "invalid_parameters",
+            Self::InvalidQuery(_) | Self::MalformedQuery(_) => "invalid_query",
+            Self::InvalidFilter(_) => "invalid_filter",
+            Self::ImageNotIndexed(_) => "image_not_indexed",
+            Self::InvalidImage(_) => "invalid_image",
+            Self::ConflictingJudgement(_) => "conflicting_judgement",
+            Self::BatchTooLarge(_) => "batch_too_large",
+            Self::InvalidLogFilter(_) => "invalid_log_filter",
+            Self::FeedbackNotFound(_) => "feedback_not_found",
+            Self::InvalidToken(_) => "invalid_token",
+            Self::MismatchedToken(_) => "mismatched_token",
+            Self::Clip(_) => "clip_model_unavailable",
+            Self::Qdrant(_) => "vector_store_unavailable",
+            Self::Database(_) => "database_unavailable",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::InvalidBody(rejection) => rejection.status(),
+            Self::InvalidQuery(_)
+            | Self::InvalidParameters(_)
+            | Self::MalformedQuery(_)
+            | Self::InvalidFilter(_)
+            | Self::ImageNotIndexed(_)
+            | Self::InvalidImage(_)
+            | Self::ConflictingJudgement(_)
+            | Self::BatchTooLarge(_)
+            | Self::InvalidLogFilter(_)
+            | Self::MismatchedToken(_) => StatusCode::BAD_REQUEST,
+            Self::FeedbackNotFound(_) => StatusCode::NOT_FOUND,
+            Self::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+            Self::Clip(e) => clip_status(e),
+            Self::Qdrant(e) => qdrant_status(e),
+            Self::Database(e) => database_status(e),
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Upstream failures are not the caller's business, they only get a generic detail
+    pub fn detail(&self, status: StatusCode) -> String {
+        if status.is_server_error() {
+            match self {
+                Self::Clip(_) => "the CLIP model failed to embed the query".to_string(),
+                Self::Qdrant(_) => "the vector store failed to process the request".to_string(),
+                Self::Database(_) => "the database failed to process the request".to_string(),
+                _ => "the server failed to process the request".to_string(),
+            }
+        } else {
+            self.to_string()
+        }
+    }
+}
+
+// The tracing span of the request carries its request ID, see `xlib::app::middleware`
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let code = self.code();
+        if status.is_server_error() {
+            tracing::error!(code, status = status.as_u16(), "{:#}", self);
+        } else {
+            tracing::info!(code, status = status.as_u16(), "{}", self);
+        }
+
+        Problem::new(status, code, self.detail(status)).into_response()
+    }
+}
+
+fn clip_status(e: &ClipError) -> StatusCode {
+    match e {
+        ClipError::Http(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+        ClipError::Http(e) if e.is_connect() => StatusCode::SERVICE_UNAVAILABLE,
+        ClipError::Status(StatusCode::SERVICE_UNAVAILABLE) => StatusCode::SERVICE_UNAVAILABLE,
+        ClipError::Http(_) | ClipError::Status(_) | ClipError::VectorCount { .. } => {
+            StatusCode::BAD_GATEWAY
+        }
+    }
+}
+
+fn qdrant_status(e: &QdrantError) -> StatusCode {
+    match e {
+        QdrantError::ResponseError { status } => match status.code() {
+            tonic::Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            tonic::Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        },
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn database_status(e: &anyhow::Error) -> StatusCode {
+    match e.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::PoolTimedOut | sqlx::Error::Io(_)) => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
@@ -0,0 +1,123 @@
+use axum::{
+    extract::{FromRequest, FromRequestParts, Path, Query, Request},
+    http::request::Parts,
+    Json,
+};
+use serde::de::DeserializeOwned;
+
+use crate::error::ApiError;
+
+// Same as axum's extractors, with the rejections rendered as problems like the other errors
+// instead of plain text
+
+pub struct ApiJson<T>(pub T);
+
+impl<T, S> FromRequest<S> for ApiJson<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
+
+pub struct ApiQuery<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for ApiQuery<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Query(value) = Query::<T>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| ApiError::InvalidParameters(e.body_text()))?;
+        Ok(Self(value))
+    }
+}
+
+pub struct ApiPath<T>(pub T);
+
+impl<T, S> FromRequestParts<S> for ApiPath<T>
+where
+    T: DeserializeOwned + Send,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let Path(value) = Path::<T>::from_request_parts(parts, state)
+            .await
+            .map_err(|e| ApiError::InvalidParameters(e.body_text()))?;
+        Ok(Self(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, StatusCode},
+        response::IntoResponse,
+    };
+
+    use super::*;
+
+    #[derive(serde::Deserialize)]
+    struct Payload {
+        #[allow(dead_code)]
+        text: String,
+    }
+
+    async fn reject(content_type: &str, body: &'static str) -> (StatusCode, String, String) {
+        let request = Request::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        let Err(error) = ApiJson::<Payload>::from_request(request, &()).await else {
+            panic!("the body is valid");
+        };
+        let code = error.code().to_string();
+        let response = error.into_response();
+        let content_type = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        (response.status(), code, content_type)
+    }
+
+    #[tokio::test]
+    async fn rejections_are_problems() {
+        let problem = "application/problem+json".to_string();
+        assert_eq!(
+            reject("application/json", "{").await,
+            (
+                StatusCode::BAD_REQUEST,
+                "invalid_body".to_string(),
+                problem.clone()
+            )
+        );
+        assert_eq!(
+            reject("application/json", r#"{"txt": "beach"}"#).await,
+            (
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "invalid_body".to_string(),
+                problem.clone()
+            )
+        );
+        assert_eq!(
+            reject("text/plain", r#"{"text": "beach"}"#).await,
+            (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "invalid_body".to_string(),
+                problem
+            )
+        );
+    }
+}
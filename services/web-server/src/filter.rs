@@ -0,0 +1,303 @@
+use chrono::{DateTime, Utc};
+use qdrant_client::qdrant::{
+    Condition, DatetimeRange, Filter, GeoBoundingBox, GeoPoint, GeoRadius, Range, Timestamp,
+};
+use serde::Deserialize;
+
+// Structured constraints on the image metadata, applied on top of the semantic query.
+// Every constraint is optional and all of the given constraints must hold.
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SearchFilter {
+    pub captured_at: Option<DateRange>,
+    pub width: Option<NumberRange>,
+    pub height: Option<NumberRange>,
+    // width / height, e.g. `{"gt": 1.0}` for landscape images only
+    pub aspect_ratio: Option<NumberRange>,
+    // any of the given formats, e.g. `["jpeg", "png"]`
+    #[serde(default)]
+    pub format: Vec<String>,
+    // any of the given folders, relative to the images root (`""` is the root itself)
+    #[serde(default)]
+    pub folder: Vec<String>,
+    // all of the given tags
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub geo: Option<GeoFilter>,
+}
+
+// Constraint on the GPS position of the image, images without GPS data never match
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum GeoFilter {
+    // e.g. `{"radius": {"center": {"lat": 25.03, "lon": 121.56}, "meters": 500}}`
+    Radius {
+        center: Coordinates,
+        meters: f32,
+    },
+    BoundingBox {
+        top_left: Coordinates,
+        bottom_right: Coordinates,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct Coordinates {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum FilterError {
+    #[error(
+        "invalid coordinates: latitude must be within [-90, 90] and longitude within [-180, 180]"
+    )]
+    InvalidCoordinates,
+    #[error("invalid geo radius: must be a positive number of meters")]
+    InvalidRadius,
+    #[error(
+        "invalid geo bounding box: the top left corner must not be south of the bottom right one"
+    )]
+    InvertedBoundingBox,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct NumberRange {
+    pub gt: Option<f64>,
+    pub gte: Option<f64>,
+    pub lt: Option<f64>,
+    pub lte: Option<f64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DateRange {
+    pub gt: Option<DateTime<Utc>>,
+    pub gte: Option<DateTime<Utc>>,
+    pub lt: Option<DateTime<Utc>>,
+    pub lte: Option<DateTime<Utc>>,
+}
+
+impl SearchFilter {
+    // Safe search only keeps the images the worker scored as safe, images that are not scored
+    // yet are excluded as well
+    pub fn to_qdrant_filter(&self, safe_search: bool) -> Result<Option<Filter>, FilterError> {
+        let mut conditions = Vec::new();
+
+        if safe_search {
+            conditions.push(Condition::matches("safety_flagged", false));
+        }
+
+        if let Some(range) = &self.captured_at {
+            conditions.push(Condition::datetime_range("captured_at", range.into()));
+        }
+        if let Some(range) = &self.width {
+            conditions.push(Condition::range("width", range.into()));
+        }
+        if let Some(range) = &self.height {
+            conditions.push(Condition::range("height", range.into()));
+        }
+        if let Some(range) = &self.aspect_ratio {
+            conditions.push(Condition::range("aspect_ratio", range.into()));
+        }
+        if !self.format.is_empty() {
+            let formats = self
+                .format
+                .iter()
+                .map(|f| f.to_lowercase())
+                .collect::<Vec<_>>();
+            conditions.push(Condition::matches("format", formats));
+        }
+        if !self.folder.is_empty() {
+            let folders = self
+                .folder
+                .iter()
+                .map(|f| f.trim_matches('/').to_string())
+                .collect::<Vec<_>>();
+            conditions.push(Condition::matches("folder", folders));
+        }
+        for tag in &self.tags {
+            conditions.push(Condition::matches("tags[].label", tag.clone()));
+        }
+        if let Some(geo) = &self.geo {
+            conditions.push(geo.to_condition()?);
+        }
+
+        Ok((!conditions.is_empty()).then(|| Filter::must(conditions)))
+    }
+}
+
+impl GeoFilter {
+    fn to_condition(&self) -> Result<Condition, FilterError> {
+        match self {
+            Self::Radius { center, meters } => {
+                if !meters.is_finite() || *meters <= 0.0 {
+                    return Err(FilterError::InvalidRadius);
+                }
+                Ok(Condition::geo_radius(
+                    "location",
+                    GeoRadius {
+                        center: Some(center.to_geo_point()?),
+                        radius: *meters,
+                    },
+                ))
+            }
+            // the longitudes are not compared, a box can cross the antimeridian
+            Self::BoundingBox {
+                top_left,
+                bottom_right,
+            } => {
+                let top_left = top_left.to_geo_point()?;
+                let bottom_right = bottom_right.to_geo_point()?;
+                if top_left.lat < bottom_right.lat {
+                    return Err(FilterError::InvertedBoundingBox);
+                }
+                Ok(Condition::geo_bounding_box(
+                    "location",
+                    GeoBoundingBox {
+                        top_left: Some(top_left),
+                        bottom_right: Some(bottom_right),
+                    },
+                ))
+            }
+        }
+    }
+}
+
+impl Coordinates {
+    fn to_geo_point(self) -> Result<GeoPoint, FilterError> {
+        if !(-90.0..=90.0).contains(&self.lat) || !(-180.0..=180.0).contains(&self.lon) {
+            return Err(FilterError::InvalidCoordinates);
+        }
+        Ok(GeoPoint {
+            lon: self.lon,
+            lat: self.lat,
+        })
+    }
+}
+
+impl From<&NumberRange> for Range {
+    fn from(range: &NumberRange) -> Self {
+        Self {
+            gt: range.gt,
+            gte: range.gte,
+            lt: range.lt,
+            lte: range.lte,
+        }
+    }
+}
+
+impl From<&DateRange> for DatetimeRange {
+    fn from(range: &DateRange) -> Self {
+        Self {
+            gt: range.gt.map(to_timestamp),
+            gte: range.gte.map(to_timestamp),
+            lt: range.lt.map(to_timestamp),
+            lte: range.lte.map(to_timestamp),
+        }
+    }
+}
+
+fn to_timestamp(date_time: DateTime<Utc>) -> Timestamp {
+    Timestamp {
+        seconds: date_time.timestamp(),
+        nanos: i32::try_from(date_time.timestamp_subsec_nanos()).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(json: serde_json::Value) -> SearchFilter {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn condition_count(filter: &SearchFilter, safe_search: bool) -> usize {
+        filter
+            .to_qdrant_filter(safe_search)
+            .unwrap()
+            .map_or(0, |filter| filter.must.len())
+    }
+
+    #[test]
+    fn empty_filter_without_safe_search_is_none() {
+        assert!(SearchFilter::default()
+            .to_qdrant_filter(false)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn every_constraint_is_a_condition() {
+        let filter = filter(serde_json::json!({
+            "width": {"gte": 100.0},
+            "format": ["JPEG"],
+            "folder": ["/trips/"],
+            "tags": ["beach", "sunset"],
+        }));
+        // one per tag, plus safe search
+        assert_eq!(condition_count(&filter, false), 5);
+        assert_eq!(condition_count(&filter, true), 6);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected() {
+        assert!(
+            serde_json::from_value::<SearchFilter>(serde_json::json!({"colour": "red"})).is_err()
+        );
+    }
+
+    #[test]
+    fn bounding_box() {
+        let bounding_box = |top: f64, bottom: f64| {
+            filter(serde_json::json!({"geo": {"bounding_box": {
+                "top_left": {"lat": top, "lon": 121.0},
+                "bottom_right": {"lat": bottom, "lon": 122.0},
+            }}}))
+            .to_qdrant_filter(false)
+        };
+        assert!(bounding_box(25.5, 24.5).is_ok());
+        assert!(matches!(
+            bounding_box(24.5, 25.5),
+            Err(FilterError::InvertedBoundingBox)
+        ));
+    }
+
+    #[test]
+    fn bounding_box_can_cross_the_antimeridian() {
+        let filter = filter(serde_json::json!({"geo": {"bounding_box": {
+            "top_left": {"lat": 10.0, "lon": 170.0},
+            "bottom_right": {"lat": -10.0, "lon": -170.0},
+        }}}));
+        assert!(filter.to_qdrant_filter(false).is_ok());
+    }
+
+    #[test]
+    fn radius_must_be_positive() {
+        for meters in [0.0, -1.0] {
+            let filter = filter(serde_json::json!({"geo": {"radius": {
+                "center": {"lat": 25.0, "lon": 121.0},
+                "meters": meters,
+            }}}));
+            assert!(matches!(
+                filter.to_qdrant_filter(false),
+                Err(FilterError::InvalidRadius)
+            ));
+        }
+    }
+
+    #[test]
+    fn coordinates_must_be_on_earth() {
+        let filter = filter(serde_json::json!({"geo": {"radius": {
+            "center": {"lat": 91.0, "lon": 121.0},
+            "meters": 500.0,
+        }}}));
+        assert!(matches!(
+            filter.to_qdrant_filter(false),
+            Err(FilterError::InvalidCoordinates)
+        ));
+    }
+}
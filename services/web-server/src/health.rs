@@ -0,0 +1,37 @@
+use axum::{extract::State, routing::get, Router};
+use xlib::app::health::{check_collection, liveness, probe, Readiness};
+
+use crate::AppState;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/api/v1/health/live", get(liveness))
+        .route("/api/v1/health/ready", get(readiness_handler))
+        // kept for the clients of the former health check, which only told that the server runs
+        .route("/api/v1/healthcheck", get(liveness))
+}
+
+async fn readiness_handler(State(state): State<AppState>) -> Readiness {
+    let timeout = state.config.health.probe_timeout();
+    let (database, qdrant, clip) = tokio::join!(
+        probe(timeout, async {
+            sqlx::query("SELECT 1")
+                .execute(&**state.pg_client)
+                .await
+                .map(|_| ())
+        }),
+        probe(
+            timeout,
+            check_collection(
+                &state.qdrant_client,
+                &state.config.qdrant.collection,
+                state.config.qdrant.vector_size,
+            )
+        ),
+        probe(timeout, state.clip_client.health_check()),
+    );
+
+    [("database", database), ("qdrant", qdrant), ("clip", clip)]
+        .into_iter()
+        .collect()
+}
@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use qdrant_client::{
+    qdrant::{Condition, Filter, MinShould, PointId, ScoredPoint, ScrollPointsBuilder, Value},
+    Qdrant, QdrantError,
+};
+use xlib::app::metrics::observe;
+
+// Number of matches taken from each of the vector and lexical searches before the fusion
+pub const HYBRID_CANDIDATES: u64 = 100;
+// The lexical search runs one scroll per token count, a query can't ask for more at once
+pub const MAX_LEXICAL_TOKENS: usize = 16;
+// Dampens the weight of the top ranks, 60 is the value of the original RRF paper
+const RRF_K: f32 = 60.0;
+
+// Written by the img-to-vec worker, the words of the folders and file name
+const SEARCH_TEXT_FIELD: &str = "search_text";
+const TAGS_FIELD: &str = "tags[].label";
+
+// Lowercase words of the query, split the same way as the image names are by the worker so
+// that `IMG_2041` matches `img 2041`
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    for token in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_lowercase)
+    {
+        if !tokens.contains(&token) {
+            tokens.push(token);
+        }
+    }
+    tokens
+}
+
+// Images whose name, folders or tags contain any of the tokens, ranked by the number of tokens
+// they contain. A scroll returns the points in ID order, so the images matching all the tokens
+// are fetched first, then the ones matching one token less and so on until there are
+// `HYBRID_CANDIDATES`: an exact name like `IMG_2041` isn't crowded out by partial matches.
+pub async fn lexical_search(
+    qdrant_client: &Qdrant,
+    collection_name: &str,
+    filter: Option<Filter>,
+    tokens: &[String],
+    with_vectors: bool,
+) -> Result<Vec<ScoredPoint>, QdrantError> {
+    let token_conditions = tokens
+        .iter()
+        .map(|token| {
+            Condition::from(Filter::should([
+                Condition::matches_text(SEARCH_TEXT_FIELD, token.clone()),
+                Condition::matches(TAGS_FIELD, token.clone()),
+            ]))
+        })
+        .collect::<Vec<_>>();
+    let filter = filter.unwrap_or_default();
+    let mut points: Vec<ScoredPoint> = Vec::new();
+
+    for min_count in (1..=tokens.len()).rev() {
+        let Some(remaining) = HYBRID_CANDIDATES
+            .checked_sub(points.len() as u64)
+            .filter(|remaining| *remaining > 0)
+        else {
+            break;
+        };
+        let mut tier_filter = filter.clone();
+        tier_filter.min_should = Some(MinShould {
+            conditions: token_conditions.clone(),
+            min_count: min_count as u64,
+        });
+        // the images of the previous tiers match more tokens
+        if !points.is_empty() {
+            let found: Vec<PointId> = points.iter().filter_map(|p| p.id.clone()).collect();
+            tier_filter.must_not.push(Condition::has_id(found));
+        }
+
+        let response = observe(
+            "qdrant",
+            "scroll",
+            qdrant_client.scroll(
+                ScrollPointsBuilder::new(collection_name)
+                    .filter(tier_filter)
+                    .limit(u32::try_from(remaining).unwrap_or(u32::MAX))
+                    .with_payload(true)
+                    .with_vectors(with_vectors),
+            ),
+        )
+        .await?;
+
+        points.extend(response.result.into_iter().map(|point| {
+            #[allow(clippy::cast_precision_loss)]
+            let score = matched_tokens(&point.payload, tokens) as f32;
+            ScoredPoint {
+                id: point.id,
+                payload: point.payload,
+                vectors: point.vectors,
+                score,
+                ..Default::default()
+            }
+        }));
+    }
+
+    // stable, the tiers are already in order
+    points.sort_by(|a, b| b.score.total_cmp(&a.score));
+    Ok(points)
+}
+
+fn matched_tokens(payload: &HashMap<String, Value>, tokens: &[String]) -> usize {
+    let mut words = payload
+        .get(SEARCH_TEXT_FIELD)
+        .and_then(|v| v.as_str())
+        .map(|text| tokenize(text))
+        .unwrap_or_default();
+    if let Some(tags) = payload.get("tags").and_then(Value::try_list_iter) {
+        words.extend(
+            tags.filter_map(|tag| tag.get_value("label")?.as_str())
+                .map(|label| label.to_lowercase()),
+        );
+    }
+    tokens.iter().filter(|t| words.contains(t)).count()
+}
+
+// Reciprocal rank fusion: each image scores `1 / (k + rank)` in every list it appears in, the
+// ranks are all that matter so the cosine and lexical scores don't need to be comparable
+pub fn reciprocal_rank_fusion(
+    rankings: impl IntoIterator<Item = Vec<ScoredPoint>>,
+    limit: u64,
+) -> Vec<ScoredPoint> {
+    let mut fused: HashMap<String, ScoredPoint> = HashMap::new();
+    for ranking in rankings {
+        for (rank, mut point) in ranking.into_iter().enumerate() {
+            let Some(image_name) = point
+                .payload
+                .get("image_name")
+                .and_then(|v| v.as_str())
+                .cloned()
+            else {
+                continue;
+            };
+            #[allow(clippy::cast_precision_loss)]
+            let score = 1.0 / (RRF_K + rank as f32 + 1.0);
+            fused
+                .entry(image_name)
+                .and_modify(|p| p.score += score)
+                .or_insert_with(|| {
+                    point.score = score;
+                    point
+                });
+        }
+    }
+
+    let mut points = fused.into_values().collect::<Vec<_>>();
+    points.sort_by(|a, b| b.score.total_cmp(&a.score));
+    points.truncate(usize::try_from(limit).unwrap_or(usize::MAX));
+    points
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(image_name: &str) -> ScoredPoint {
+        ScoredPoint {
+            payload: HashMap::from([("image_name".to_string(), Value::from(image_name))]),
+            ..Default::default()
+        }
+    }
+
+    fn image_names(points: &[ScoredPoint]) -> Vec<&str> {
+        points
+            .iter()
+            .filter_map(|p| p.payload.get("image_name")?.as_str().map(String::as_str))
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_splits_like_the_worker() {
+        assert_eq!(tokenize("IMG_2041 beach/Beach"), ["img", "2041", "beach"]);
+        // as `search_text` in the worker
+        assert_eq!(
+            tokenize("2023/beach/IMG_2041"),
+            ["2023", "beach", "img", "2041"]
+        );
+        assert_eq!(tokenize("Été/Plage-Bleue"), ["été", "plage", "bleue"]);
+    }
+
+    #[test]
+    fn matched_tokens_counts_the_words_and_tags() {
+        let mut payload = point("beach/IMG_2041.jpg").payload;
+        payload.insert(
+            SEARCH_TEXT_FIELD.to_string(),
+            Value::from("beach img 2041 jpg"),
+        );
+        let tokens = tokenize("img 2041 sunset dog");
+        assert_eq!(matched_tokens(&payload, &tokens), 2);
+    }
+
+    #[test]
+    fn rrf_favours_the_images_of_both_rankings() {
+        let vector = vec![point("a"), point("b"), point("c")];
+        let lexical = vec![point("c"), point("d")];
+        let fused = reciprocal_rank_fusion([vector, lexical], 10);
+        // b and d tie, both are second of one ranking
+        assert_eq!(image_names(&fused[..2]), ["c", "a"]);
+        assert_eq!(fused.len(), 4);
+        let c = 1.0 / (RRF_K + 3.0) + 1.0 / (RRF_K + 1.0);
+        assert!((fused[0].score - c).abs() < f32::EPSILON);
+    }
+
+    #[test]
+    fn rrf_keeps_the_limit() {
+        let fused = reciprocal_rank_fusion([vec![point("a"), point("b"), point("c")]], 2);
+        assert_eq!(image_names(&fused), ["a", "b"]);
+    }
+}
@@ -1,32 +1,50 @@
 #![allow(clippy::redundant_pub_crate)]
 
-use anyhow::Error;
-
 use axum::{
-    extract::{Json, State},
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    routing::{get, post},
+    extract::{DefaultBodyLimit, Json, State},
+    routing::post,
     Router,
 };
-use serde_json::json;
-use tracing::info;
+use metrics_exporter_prometheus::PrometheusHandle;
+use qdrant_client::Qdrant;
 use xlib::{
-    app::serve::serve_service,
-    client::{PostgresClient, PostgresClientConfig},
+    app::{middleware::with_request_tracing, serve::serve_service, tracing::LogFilter},
+    client::{
+        ClipClient, ClipClientConfig, PostgresClient, PostgresClientConfig, RedisClient,
+        RedisClientConfig,
+    },
 };
 
 use serde::{Deserialize, Serialize};
 use std::{
-    env,
-    net::{Ipv4Addr, SocketAddrV4},
-    sync::Arc,
+    future::Future,
+    sync::{Arc, OnceLock},
 };
+mod admin;
+mod batch;
+mod config;
+mod embedding_cache;
+mod error;
+mod extract;
+mod filter;
+mod health;
+mod hybrid;
+mod metadata;
+mod mmr;
+mod points;
+mod query;
+mod refine;
 mod repo;
+mod search;
 
+use batch::batch_search_image_handler;
+use config::{AppConfig, DatabaseConfig, QdrantConfig, RedisConfig, StartupConfig};
+use embedding_cache::EmbeddingCache;
+use error::ApiError;
+use extract::ApiJson;
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
-use qdrant_client::qdrant::QueryPointsBuilder;
-use qdrant_client::Qdrant;
+use refine::refine_search_handler;
+use search::search_image_handler;
 
 #[derive(Deserialize, Serialize, Clone)]
 struct CreateFeedbackRequest {
@@ -34,65 +52,35 @@ struct CreateFeedbackRequest {
     user_feedback: i32,
 }
 
-#[derive(Deserialize, Serialize, Clone)]
-struct CreateFeedbackResponse {
-    id: i32,
-}
-
 #[derive(Clone)]
 struct AppState {
+    pub config: Arc<AppConfig>,
     pub pg_client: Arc<PostgresClient>,
-}
-
-#[derive(Deserialize)]
-struct SearchImageRequest {
-    text: String,
-}
-
-#[derive(Serialize)]
-struct SearchImageResponse {
-    text: String,
-    model_name: String,
-    matches: Vec<ImageMatch>,
-    jwt: String,
-}
-
-#[derive(Serialize)]
-struct ImageMatch {
-    image_name: String,
-    score: f32,
+    pub embedding_cache: Arc<EmbeddingCache>,
+    // Shared by all the requests, both keep a pool of connections
+    pub qdrant_client: Arc<Qdrant>,
+    pub clip_client: ClipClient,
+    pub log_filter: LogFilter,
 }
 
 // TODO: Inject this secret via environment variables and keep it secure for production deployment
 const JWT_SECRET: &str = "jwt_secret";
 async fn create_feedback_handler(
     State(state): State<AppState>,
-    Json(payload): Json<CreateFeedbackRequest>,
-) -> Response {
-    let claims = match decode::<Claims>(
-        &payload.jwt,
-        &DecodingKey::from_secret(JWT_SECRET.as_bytes()),
-        &Validation::default(),
-    ) {
-        Ok(c) => c,
-        Err(e) => {
-            println!("Error decoding JWT: {}", e);
-            return StatusCode::UNAUTHORIZED.into_response();
-        }
-    };
+    ApiJson(payload): ApiJson<CreateFeedbackRequest>,
+) -> Result<Json<i32>, ApiError> {
+    let claims = decode_jwt(&payload.jwt)?;
     let repo = repo::Repo::new(state.pg_client);
-    let r = repo
+    let id = repo
         .create_feedback(
-            claims.claims.image_name,
-            claims.claims.text,
-            claims.claims.model_name,
+            claims.text,
+            claims.image_name,
+            claims.model_name,
             payload.user_feedback,
         )
-        .await;
-    match r {
-        Ok(id) => axum::Json(id).into_response(),
-        Err(_) => Json(json!("create feedback failed")).into_response(),
-    }
+        .await
+        .map_err(ApiError::Database)?;
+    Ok(Json(id))
 }
 #[derive(Serialize, Deserialize)]
 struct Claims {
@@ -104,6 +92,16 @@ struct Claims {
     score: f32,
 }
 
+// The token of a search match, see `create_jwt`
+fn decode_jwt(token: &str) -> Result<Claims, ApiError> {
+    let token = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(JWT_SECRET.as_bytes()),
+        &Validation::default(),
+    )?;
+    Ok(token.claims)
+}
+
 fn create_jwt(
     secret: &str,
     image_name: String,
@@ -133,125 +131,191 @@ fn create_jwt(
     .unwrap()
 }
 
-async fn search_image_handler(
-    State(state): State<AppState>,
-    Json(payload): Json<SearchImageRequest>,
-) -> Response {
-    let client = reqwest::Client::new();
-    let qdrant_client = Qdrant::from_url("http://qdrant:6334").build().unwrap();
-    let collection_name = "clip_images_collection";
-    let clip_request = serde_json::json!({
-        "text": payload.text
-    });
-
-    let response = match client
-        .post("http://clip-model:8000/api/v1/clip/text-to-vector")
-        .json(&clip_request)
-        .send()
-        .await
-    {
-        Ok(resp) => resp,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+async fn init_db(config: &DatabaseConfig) -> PostgresClient {
+    let db_config = PostgresClientConfig {
+        hostname: config.hostname.clone(),
+        port: config.port,
+        user: config.user.clone(),
+        password: config.password.clone(),
+        db_name: config.name.clone(),
+        max_connections: Some(config.max_connections),
+        acquire_timeout: Some(config.acquire_timeout()),
     };
+    PostgresClient::build(&db_config).await.unwrap()
+}
 
-    let text_vector_response = match response.json::<serde_json::Value>().await {
-        Ok(json) => json,
-        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+// A Redis that can't be reached at startup is connected in the background, the embedding cache is
+// in process only until then. Once connected, the client reconnects by itself.
+fn init_redis(config: &RedisConfig, startup: &StartupConfig) -> Arc<OnceLock<RedisClient>> {
+    let redis = Arc::new(OnceLock::new());
+    let Some(hostname) = config.hostname.clone() else {
+        tracing::info!("No Redis hostname, the embedding cache is in process only");
+        return redis;
     };
-
-    let text_vector: Vec<f32> = match text_vector_response.get("vector") {
-        Some(v) => v
-            .as_array()
-            .unwrap()
-            .iter()
-            .filter_map(|v| v.as_f64().map(|x| x as f32))
-            .collect(),
-        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    let redis_config = RedisClientConfig {
+        hostname,
+        port: config.port,
+        password: config.password.clone(),
+        response_timeout: Some(config.timeout()),
+        connection_timeout: Some(config.timeout()),
+        ..Default::default()
     };
+    let retry_interval = startup.health_check_interval();
+    tokio::spawn({
+        let redis = redis.clone();
+        async move {
+            loop {
+                match RedisClient::build(&redis_config).await {
+                    Ok(client) => {
+                        let _ = redis.set(client);
+                        return;
+                    }
+                    Err(e) => {
+                        tracing::warn!("{:#}, the embedding cache is in process only", e);
+                        tokio::time::sleep(retry_interval).await;
+                    }
+                }
+            }
+        }
+    });
+    redis
+}
 
-    let query = QueryPointsBuilder::new(collection_name)
-        .query(text_vector)
-        .with_payload(true);
-
-    let search_result = qdrant_client.query(query).await.unwrap();
-    let first_result = match search_result.result.first() {
-        Some(r) => r,
-        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-    };
-    let image_name = match first_result.payload.get("image_name") {
-        Some(v) => match v.as_str() {
-            Some(s) => s.to_string(),
-            None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-        },
-        None => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
-    };
-    let score = first_result.score;
-    let mut response = SearchImageResponse {
-        text: payload.text.clone(),
-        model_name: "CLIP".to_string(),
-        matches: vec![ImageMatch {
-            image_name: image_name.clone(),
-            score,
-        }],
-        jwt: String::new(),
-    };
+// Retry `health_check` until it succeeds, panics once the attempts are exhausted
+async fn wait_until_healthy<F, Fut, E>(config: &StartupConfig, name: &str, mut health_check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+    E: std::fmt::Display,
+{
+    for attempt in 1..=config.health_check_attempts {
+        match health_check().await {
+            Ok(()) => {
+                tracing::info!("{} is healthy", name);
+                return;
+            }
+            Err(e) if attempt < config.health_check_attempts => {
+                tracing::warn!(
+                    "{} is not healthy yet ({}/{}): {}",
+                    name,
+                    attempt,
+                    config.health_check_attempts,
+                    e
+                );
+                tokio::time::sleep(config.health_check_interval()).await;
+            }
+            Err(e) => panic!("{name} is not healthy: {e}"),
+        }
+    }
+}
 
-    let jwt = create_jwt(
-        JWT_SECRET,
-        image_name,
-        payload.text,
-        "CLIP".to_string(),
-        score,
-    );
-    response.jwt = jwt;
-    Json(response).into_response()
+async fn init_qdrant(config: &QdrantConfig, startup: &StartupConfig) -> Qdrant {
+    let qdrant_client = Qdrant::from_url(&config.url)
+        .timeout(config.timeout())
+        .connect_timeout(config.connect_timeout())
+        .keep_alive_while_idle()
+        .build()
+        .expect("invalid Qdrant configuration");
+    wait_until_healthy(startup, "Qdrant", || async {
+        qdrant_client.health_check().await.map(|_| ())
+    })
+    .await;
+    qdrant_client
 }
 
-async fn init_db() -> PostgresClient {
-    let db_config = PostgresClientConfig {
-        hostname: env::var("DATABASE_HOSTNAME").expect("DATABASE_HOSTNAME not found."),
-        port: None,
-        user: Some(env::var("DATABASE_USER").expect("DATABASE_USER not found.")),
-        password: Some(env::var("DATABASE_PASSWORD").expect("DATABASE_PASSWORD not found.")),
-        db_name: "web-server".to_string(),
+async fn init_clip(config: &AppConfig) -> ClipClient {
+    let clip_config = ClipClientConfig {
+        url: config.clip.url.clone(),
+        timeout: config.clip.timeout(),
+        connect_timeout: config.clip.connect_timeout(),
+        pool_max_idle_per_host: Some(config.clip.pool_max_idle_per_host),
+        batch_size: Some(config.clip.batch_size),
     };
-    PostgresClient::build(&db_config).await.unwrap()
+    let clip_client = ClipClient::new(&clip_config).expect("invalid CLIP client configuration");
+    wait_until_healthy(&config.startup, "CLIP model", || clip_client.health_check()).await;
+    clip_client
 }
 
-async fn start_web_server() {
-    let db_client = init_db().await;
-    let state = AppState {
-        pg_client: Arc::new(db_client.clone()),
-    };
+// The public API and the admin API, sharing the same state. `/metrics` is served with the admin
+// API, on the private port.
+fn routers(state: AppState, metrics_handle: PrometheusHandle) -> (Router, Router) {
     let app = Router::new()
+        .route("/api/v1/search-image", post(search_image_handler))
         .route(
-            "/api/v1/healthcheck",
-            get(|| async { StatusCode::OK.into_response() }),
+            "/api/v1/batch-search-image",
+            post(batch_search_image_handler),
         )
-        .route("/api/v1/search-image", post(search_image_handler))
+        .route("/api/v1/refine-search", post(refine_search_handler))
         .route("/api/v1/create-feedback", post(create_feedback_handler))
-        .with_state(state);
+        .merge(health::router())
+        .layer(DefaultBodyLimit::max(state.config.search.max_body_bytes()))
+        .with_state(state.clone());
+    let admin_app = admin::router()
+        .with_state(state)
+        .merge(xlib::app::metrics::router(metrics_handle));
+    let app = xlib::app::metrics::track_requests(app);
+    let admin_app = xlib::app::metrics::track_requests(admin_app);
+
+    (app, admin_app)
+}
+
+async fn start_web_server(
+    config: AppConfig,
+    log_filter: LogFilter,
+    metrics_handle: PrometheusHandle,
+) {
+    let db_client = init_db(&config.database).await;
+    let qdrant_client = init_qdrant(&config.qdrant, &config.startup).await;
+    let clip_client = init_clip(&config).await;
+    let embedding_cache = EmbeddingCache::new(
+        &config.embedding_cache,
+        clip_client.clone(),
+        init_redis(&config.redis, &config.startup),
+    );
+    let public_addr = config.public_http.socket_addr();
+    let private_addr = config.private_http.socket_addr();
+    let (app, admin_app) = routers(
+        AppState {
+            config: Arc::new(config),
+            pg_client: Arc::new(db_client),
+            embedding_cache: Arc::new(embedding_cache),
+            qdrant_client: Arc::new(qdrant_client),
+            clip_client,
+            log_filter,
+        },
+        metrics_handle,
+    );
 
     let public_service = serve_service(
-        app,
-        SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 3000),
+        with_request_tracing(app),
+        public_addr,
         "public image search service",
     );
+    let private_service = serve_service(
+        with_request_tracing(admin_app),
+        private_addr,
+        "private admin service",
+    );
 
     tokio::select! {
         _ = public_service => {}
+        _ = private_service => {}
     };
 }
 
 fn main() {
-    tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
-        .with_writer(std::io::stdout)
-        .init();
-
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()
-        .block_on(start_web_server());
+        .block_on(async {
+            // within the runtime, the OTLP exporter runs on it
+            let (log_filter, tracing) = xlib::app::tracing::setup_reloadable("web-server", "info");
+            let metrics_handle = xlib::app::metrics::setup();
+
+            let config = AppConfig::load().unwrap_or_else(|e| panic!("invalid configuration: {e}"));
+
+            start_web_server(config, log_filter, metrics_handle).await;
+            tracing.shutdown().await;
+        });
 }
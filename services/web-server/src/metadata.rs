@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use qdrant_client::qdrant::Value;
+use serde::{Deserialize, Serialize};
+
+// Image metadata written into the point payload by the img-to-vec worker. Every field is
+// optional since images indexed by older workers only carry their name.
+#[derive(Deserialize, Serialize, Default)]
+pub struct ImageMetadata {
+    pub folder: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub aspect_ratio: Option<f64>,
+    pub format: Option<String>,
+    pub file_size: Option<u64>,
+    pub modified_at: Option<DateTime<Utc>>,
+    pub captured_at: Option<DateTime<Utc>>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+    pub gps_altitude: Option<f64>,
+    // Zero-shot labels computed by the worker, best first
+    pub tags: Option<Vec<Tag>>,
+    // Probability that the image shows unsafe content
+    pub safety: Option<f32>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub struct Tag {
+    pub label: String,
+    pub confidence: f32,
+}
+
+impl ImageMetadata {
+    pub fn from_payload(payload: HashMap<String, Value>) -> Self {
+        let payload = payload
+            .into_iter()
+            .map(|(k, v)| (k, v.into_json()))
+            .collect::<serde_json::Map<_, _>>();
+
+        serde_json::from_value(payload.into()).unwrap_or_else(|e| {
+            tracing::warn!("Failed to parse image metadata from payload: {}", e);
+            Self::default()
+        })
+    }
+}
@@ -0,0 +1,125 @@
+use qdrant_client::qdrant::ScoredPoint;
+use xlib::collection::dense_vector;
+
+// Number of candidates the diverse matches are picked from
+pub const MMR_CANDIDATES: u64 = 100;
+
+// Maximal marginal relevance: greedily pick the candidate with the best trade-off between its
+// relevance and its similarity to the already picked ones. `lambda` 1 is the plain ranking,
+// lower values favor variety, e.g. a single frame of a burst of near-identical shots.
+pub fn rerank(candidates: Vec<ScoredPoint>, lambda: f32, limit: u64) -> Vec<ScoredPoint> {
+    let limit = usize::try_from(limit).unwrap_or(usize::MAX);
+    let relevance = normalized_scores(&candidates);
+    // the stored vectors are normalized, so the dot product is the cosine similarity
+    let vectors = candidates
+        .iter()
+        .map(|point| point.vectors.clone().and_then(dense_vector))
+        .collect::<Vec<_>>();
+
+    let mut remaining = (0..candidates.len()).collect::<Vec<_>>();
+    let mut picked: Vec<usize> = Vec::with_capacity(limit.min(candidates.len()));
+    while picked.len() < limit && !remaining.is_empty() {
+        let (position, _) = remaining
+            .iter()
+            .enumerate()
+            .map(|(position, &i)| {
+                let redundancy = picked
+                    .iter()
+                    .map(|&j| similarity(vectors[i].as_deref(), vectors[j].as_deref()))
+                    .fold(0.0, f32::max);
+                (
+                    position,
+                    lambda.mul_add(relevance[i], (lambda - 1.0) * redundancy),
+                )
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or_default();
+        picked.push(remaining.remove(position));
+    }
+
+    let mut candidates = candidates.into_iter().map(Some).collect::<Vec<_>>();
+    picked
+        .into_iter()
+        .filter_map(|i| candidates[i].take())
+        .collect()
+}
+
+// Scores rescaled to [0, 1], so the relevance is comparable to the cosine similarity between
+// images whatever the kind of score (text to image cosine, fused rank)
+fn normalized_scores(points: &[ScoredPoint]) -> Vec<f32> {
+    let max = points.iter().map(|p| p.score).fold(f32::MIN, f32::max);
+    let min = points.iter().map(|p| p.score).fold(f32::MAX, f32::min);
+    let range = max - min;
+    points
+        .iter()
+        .map(|p| {
+            if range > f32::EPSILON {
+                (p.score - min) / range
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+// Candidates without a vector are never considered redundant
+fn similarity(a: Option<&[f32]>, b: Option<&[f32]>) -> f32 {
+    match (a, b) {
+        (Some(a), Some(b)) => a.iter().zip(b).map(|(x, y)| x * y).sum(),
+        _ => 0.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(score: f32, vector: Vec<f32>) -> ScoredPoint {
+        ScoredPoint {
+            score,
+            vectors: Some(vector.into()),
+            ..Default::default()
+        }
+    }
+
+    // a burst of two near-identical frames and a different image
+    fn candidates() -> Vec<ScoredPoint> {
+        vec![
+            candidate(0.9, vec![1.0, 0.0]),
+            candidate(0.85, vec![1.0, 0.0]),
+            candidate(0.5, vec![0.0, 1.0]),
+        ]
+    }
+
+    fn scores(points: &[ScoredPoint]) -> Vec<f32> {
+        points.iter().map(|p| p.score).collect()
+    }
+
+    #[test]
+    fn lambda_one_keeps_the_ranking() {
+        assert_eq!(scores(&rerank(candidates(), 1.0, 3)), [0.9, 0.85, 0.5]);
+    }
+
+    #[test]
+    fn lower_lambda_skips_the_duplicates() {
+        assert_eq!(scores(&rerank(candidates(), 0.5, 2)), [0.9, 0.5]);
+    }
+
+    #[test]
+    fn candidates_without_vectors_are_never_redundant() {
+        let candidates = vec![
+            candidate(0.9, vec![1.0, 0.0]),
+            ScoredPoint {
+                score: 0.85,
+                ..Default::default()
+            },
+        ];
+        assert_eq!(scores(&rerank(candidates, 0.5, 2)), [0.9, 0.85]);
+    }
+
+    #[test]
+    fn limit_is_kept() {
+        assert_eq!(rerank(candidates(), 0.5, 1).len(), 1);
+        assert!(rerank(Vec::new(), 0.5, 5).is_empty());
+    }
+}
@@ -0,0 +1,47 @@
+use std::collections::HashMap;
+
+use qdrant_client::{
+    qdrant::{GetPointsBuilder, PointId},
+    Qdrant, QdrantError,
+};
+use uuid::Uuid;
+use xlib::{app::metrics::observe, collection::dense_vector};
+
+// Same id as the one the img-to-vec worker gives to the point of the image
+pub fn point_id(image_name: &str) -> PointId {
+    Uuid::new_v5(&Uuid::NAMESPACE_URL, image_name.as_bytes())
+        .to_string()
+        .into()
+}
+
+// Stored vectors of the given images, by image name. Unknown images are left out.
+pub async fn fetch_vectors(
+    qdrant_client: &Qdrant,
+    collection_name: &str,
+    image_names: &[String],
+) -> Result<HashMap<String, Vec<f32>>, QdrantError> {
+    if image_names.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let ids = image_names.iter().map(|n| point_id(n)).collect::<Vec<_>>();
+    let response = observe(
+        "qdrant",
+        "get_points",
+        qdrant_client.get_points(
+            GetPointsBuilder::new(collection_name, ids)
+                .with_payload(true)
+                .with_vectors(true),
+        ),
+    )
+    .await?;
+
+    Ok(response
+        .result
+        .into_iter()
+        .filter_map(|point| {
+            let image_name = point.payload.get("image_name")?.as_str()?.clone();
+            Some((image_name, dense_vector(point.vectors?)?))
+        })
+        .collect())
+}
@@ -0,0 +1,259 @@
+use serde::Deserialize;
+
+// A text term of a multi-text query. Negative weights push the results away from the term,
+// e.g. "beach" +1.0 and "people" -0.5 for empty beaches.
+#[derive(Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WeightedTerm {
+    pub text: String,
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+}
+
+const fn default_weight() -> f32 {
+    1.0
+}
+
+// Reference image of a composed query, e.g. "this sofa but in red"
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum ImageReference {
+    // An image already indexed by the worker
+    ImageName(String),
+    // An uploaded image
+    Base64(String),
+}
+
+// Share of the reference image in a composed query, the text terms get the rest
+pub const DEFAULT_IMAGE_WEIGHT: f32 = 0.5;
+// Every term is embedded by the CLIP model, a query can't ask for more at once
+pub const MAX_QUERY_TERMS: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+    #[error("the query needs at least one term with a positive weight")]
+    NoPositiveTerm,
+    #[error("query terms must not be empty")]
+    EmptyTerm,
+    #[error("a query holds at most {MAX_QUERY_TERMS} terms")]
+    TooManyTerms,
+    #[error(
+        "a hybrid query holds at most {} words",
+        crate::hybrid::MAX_LEXICAL_TOKENS
+    )]
+    TooManyTokens,
+    #[error("term weights must be finite numbers")]
+    InvalidWeight,
+    #[error("the image weight must be between 0 and 1")]
+    InvalidImageWeight,
+    #[error("the query needs a text term or a reference image")]
+    Empty,
+    #[error("the MMR lambda must be between 0 and 1")]
+    InvalidMmrLambda,
+    #[error("the minimum score must be a finite number")]
+    InvalidMinScore,
+    #[error("the weighted terms cancel each other out, the query vector is zero")]
+    ZeroVector,
+}
+
+// Human readable form of the query, stored along with the feedback
+pub fn describe(terms: &[WeightedTerm], image: Option<&ImageReference>) -> String {
+    let text = match terms {
+        [term] if (term.weight - 1.0).abs() < f32::EPSILON => term.text.clone(),
+        _ => terms
+            .iter()
+            .map(|t| format!("{} ({:+})", t.text, t.weight))
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+
+    match image {
+        None => text,
+        Some(image) => {
+            let image = match image {
+                ImageReference::ImageName(name) => format!("[image: {name}]"),
+                ImageReference::Base64(_) => "[uploaded image]".to_string(),
+            };
+            if text.is_empty() {
+                image
+            } else {
+                format!("{image} {text}")
+            }
+        }
+    }
+}
+
+// A query is either text terms, a reference image, or both
+pub fn validate(
+    terms: &[WeightedTerm],
+    image: Option<&ImageReference>,
+    image_weight: f32,
+) -> Result<(), QueryError> {
+    if !(0.0..=1.0).contains(&image_weight) {
+        return Err(QueryError::InvalidImageWeight);
+    }
+    if terms.is_empty() {
+        return if image.is_some() {
+            Ok(())
+        } else {
+            Err(QueryError::Empty)
+        };
+    }
+    if terms.len() > MAX_QUERY_TERMS {
+        return Err(QueryError::TooManyTerms);
+    }
+    if terms.iter().any(|t| t.text.trim().is_empty()) {
+        return Err(QueryError::EmptyTerm);
+    }
+    if terms.iter().any(|t| !t.weight.is_finite()) {
+        return Err(QueryError::InvalidWeight);
+    }
+    if !terms.iter().any(|t| t.weight > 0.0) {
+        return Err(QueryError::NoPositiveTerm);
+    }
+    Ok(())
+}
+
+// Weighted sum of the term vectors, normalized back to a unit vector for cosine search. Weights
+// that cancel each other out, e.g. the same term with +1 and -1, leave no direction to search.
+pub fn combine<'a>(
+    weighted_vectors: impl IntoIterator<Item = (&'a [f32], f32)>,
+) -> Result<Vec<f32>, QueryError> {
+    let mut combined: Vec<f32> = Vec::new();
+    for (vector, weight) in weighted_vectors {
+        if combined.is_empty() {
+            combined = vec![0.0; vector.len()];
+        }
+        for (c, v) in combined.iter_mut().zip(vector) {
+            *c += weight * v;
+        }
+    }
+    let norm = combined.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm <= f32::EPSILON {
+        return Err(QueryError::ZeroVector);
+    }
+    for v in &mut combined {
+        *v /= norm;
+    }
+    Ok(combined)
+}
+
+// Rocchio weights: the original query, the centroid of the relevant images and the centroid of
+// the irrelevant images
+const ROCCHIO_ALPHA: f32 = 1.0;
+const ROCCHIO_BETA: f32 = 0.75;
+const ROCCHIO_GAMMA: f32 = 0.15;
+
+// Move the query towards the images marked relevant and away from the ones marked irrelevant
+pub fn rocchio(
+    query: &[f32],
+    relevant: &[Vec<f32>],
+    irrelevant: &[Vec<f32>],
+) -> Result<Vec<f32>, QueryError> {
+    let centroid_weight = |weight: f32, vectors: &[Vec<f32>]| {
+        #[allow(clippy::cast_precision_loss)]
+        let count = vectors.len() as f32;
+        weight / count
+    };
+    let relevant_weight = centroid_weight(ROCCHIO_BETA, relevant);
+    let irrelevant_weight = -centroid_weight(ROCCHIO_GAMMA, irrelevant);
+
+    combine(
+        std::iter::once((query, ROCCHIO_ALPHA))
+            .chain(relevant.iter().map(|v| (v.as_slice(), relevant_weight)))
+            .chain(irrelevant.iter().map(|v| (v.as_slice(), irrelevant_weight))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(text: &str, weight: f32) -> WeightedTerm {
+        WeightedTerm {
+            text: text.to_string(),
+            weight,
+        }
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{actual:?} != {expected:?}");
+        }
+    }
+
+    #[test]
+    fn combine_is_the_normalized_weighted_sum() {
+        let beach = [1.0, 0.0, 0.0];
+        let people = [0.0, 1.0, 0.0];
+        let combined = combine([(beach.as_slice(), 3.0), (people.as_slice(), -4.0)]).unwrap();
+        assert_close(&combined, &[0.6, -0.8, 0.0]);
+    }
+
+    #[test]
+    fn combine_rejects_weights_cancelling_out() {
+        let beach = [0.6, 0.8];
+        assert!(matches!(
+            combine([(beach.as_slice(), 1.0), (beach.as_slice(), -1.0)]),
+            Err(QueryError::ZeroVector)
+        ));
+    }
+
+    #[test]
+    fn rocchio_moves_towards_the_relevant_images() {
+        let query = [1.0, 0.0, 0.0];
+        let relevant = [vec![0.0, 1.0, 0.0], vec![0.0, 1.0, 0.0]];
+        let irrelevant = [vec![0.0, 0.0, 1.0]];
+        let refined = rocchio(&query, &relevant, &irrelevant).unwrap();
+        // 1.0 * query + 0.75 * relevant centroid - 0.15 * irrelevant centroid, normalized by
+        // sqrt(1.0² + 0.75² + 0.15²)
+        let norm = 1.585f32.sqrt();
+        assert_close(&refined, &[1.0 / norm, 0.75 / norm, -0.15 / norm]);
+    }
+
+    #[test]
+    fn rocchio_without_judgements_keeps_the_query() {
+        let query = [0.6, 0.8];
+        assert_close(&rocchio(&query, &[], &[]).unwrap(), &query);
+    }
+
+    #[test]
+    fn validate_needs_a_term_or_an_image() {
+        assert!(matches!(validate(&[], None, 0.5), Err(QueryError::Empty)));
+        let image = ImageReference::ImageName("a.jpg".to_string());
+        assert!(validate(&[], Some(&image), 0.5).is_ok());
+        assert!(matches!(
+            validate(&[], Some(&image), 1.5),
+            Err(QueryError::InvalidImageWeight)
+        ));
+    }
+
+    #[test]
+    fn validate_checks_the_terms() {
+        assert!(validate(&[term("beach", 1.0), term("people", -0.5)], None, 0.5).is_ok());
+        assert!(matches!(
+            validate(&[term("  ", 1.0)], None, 0.5),
+            Err(QueryError::EmptyTerm)
+        ));
+        assert!(matches!(
+            validate(&[term("beach", f32::NAN)], None, 0.5),
+            Err(QueryError::InvalidWeight)
+        ));
+        assert!(matches!(
+            validate(&[term("people", -1.0)], None, 0.5),
+            Err(QueryError::NoPositiveTerm)
+        ));
+    }
+
+    #[test]
+    fn validate_caps_the_term_count() {
+        let terms = vec![term("beach", 1.0); MAX_QUERY_TERMS];
+        assert!(validate(&terms, None, 0.5).is_ok());
+        let terms = vec![term("beach", 1.0); MAX_QUERY_TERMS + 1];
+        assert!(matches!(
+            validate(&terms, None, 0.5),
+            Err(QueryError::TooManyTerms)
+        ));
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+
+use axum::extract::{Json, State};
+use serde::Deserialize;
+
+use crate::{
+    decode_jwt,
+    error::ApiError,
+    extract::ApiJson,
+    points, query, repo,
+    search::{self, PreparedSearch, SearchImageRequest, SearchImageResponse},
+    AppState, Claims,
+};
+
+// Feedback scores recorded for the judgements of a refinement
+const RELEVANT_FEEDBACK: i32 = 10;
+const IRRELEVANT_FEEDBACK: i32 = 0;
+
+#[derive(Deserialize)]
+pub struct RefineSearchRequest {
+    // The original search request
+    #[serde(flatten)]
+    search: SearchImageRequest,
+    // Tokens of the current results the user marked relevant or irrelevant, the `jwt` of the
+    // matches. They prove the images were returned for this query.
+    #[serde(default)]
+    relevant: Vec<String>,
+    #[serde(default)]
+    irrelevant: Vec<String>,
+}
+
+// Re-run a search with a query vector moved towards the relevant images and away from the
+// irrelevant ones. The judgements are recorded as feedback as well.
+pub async fn refine_search_handler(
+    State(state): State<AppState>,
+    ApiJson(payload): ApiJson<RefineSearchRequest>,
+) -> Result<Json<SearchImageResponse>, ApiError> {
+    let relevant_claims = decode_judgements(payload.relevant)?;
+    let irrelevant_claims = decode_judgements(payload.irrelevant)?;
+    let image_names = |judgements: &[Judgement]| {
+        judgements
+            .iter()
+            .map(|j| j.claims.image_name.clone())
+            .collect::<Vec<_>>()
+    };
+    let relevant_names = image_names(&relevant_claims);
+    let irrelevant_names = image_names(&irrelevant_claims);
+    if let Some(image_name) = relevant_names
+        .iter()
+        .find(|name| irrelevant_names.contains(name))
+    {
+        return Err(ApiError::ConflictingJudgement(image_name.clone()));
+    }
+
+    let mut search = PreparedSearch::new(&state, payload.search).await?;
+    if let Some(claims) = relevant_claims
+        .iter()
+        .chain(&irrelevant_claims)
+        .find(|judgement| judgement.claims.text != search.query_text)
+    {
+        return Err(ApiError::MismatchedToken(claims.claims.image_name.clone()));
+    }
+
+    let judged = relevant_names
+        .iter()
+        .chain(&irrelevant_names)
+        .cloned()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let mut vectors = points::fetch_vectors(
+        &state.qdrant_client,
+        &state.config.qdrant.collection,
+        &judged,
+    )
+    .await?;
+    let mut take_vectors = |image_names: &[String]| {
+        image_names
+            .iter()
+            .map(|name| {
+                vectors
+                    .remove(name)
+                    .ok_or_else(|| ApiError::ImageNotIndexed(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    };
+    let relevant = take_vectors(&relevant_names)?;
+    let irrelevant = take_vectors(&irrelevant_names)?;
+
+    search.query_vector = query::rocchio(&search.query_vector, &relevant, &irrelevant)?;
+    // the user already saw the judged images
+    search.exclude(judged.iter().map(String::as_str));
+    let min_score = search.min_score;
+
+    record_judgements(&state, relevant_claims, irrelevant_claims).await;
+
+    let response = search.run(&state).await?;
+    // recorded in the background, like in a single search
+    if response.matches.is_empty() {
+        tokio::spawn(search::record_zero_result(
+            state,
+            response.text.clone(),
+            min_score,
+        ));
+    }
+    Ok(Json(response))
+}
+
+// A judged match and the token it was returned with
+struct Judgement {
+    claims: Claims,
+    token: String,
+}
+
+// An image judged twice, e.g. the same token sent again, counts once
+fn decode_judgements(tokens: Vec<String>) -> Result<Vec<Judgement>, ApiError> {
+    let mut seen = HashSet::new();
+    let mut judgements = Vec::new();
+    for token in tokens {
+        let claims = decode_jwt(&token)?;
+        if seen.insert(claims.image_name.clone()) {
+            judgements.push(Judgement { claims, token });
+        }
+    }
+    Ok(judgements)
+}
+
+// A failure to record the feedback should not cost the user the refined results. A judgement
+// repeated by the next refinements is recorded once, see `Repo::create_judgement`.
+async fn record_judgements(state: &AppState, relevant: Vec<Judgement>, irrelevant: Vec<Judgement>) {
+    let repo = repo::Repo::new(state.pg_client.clone());
+    let judgements = relevant
+        .into_iter()
+        .map(|judgement| (judgement, RELEVANT_FEEDBACK))
+        .chain(
+            irrelevant
+                .into_iter()
+                .map(|judgement| (judgement, IRRELEVANT_FEEDBACK)),
+        );
+    for (Judgement { claims, token }, feedback) in judgements {
+        if let Err(e) = repo
+            .create_judgement(
+                claims.text,
+                claims.image_name.clone(),
+                claims.model_name,
+                feedback,
+                token,
+            )
+            .await
+        {
+            tracing::error!(
+                "Failed to record the feedback on {}: {}",
+                claims.image_name,
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{create_jwt, JWT_SECRET};
+
+    fn token(image_name: &str) -> String {
+        create_jwt(
+            JWT_SECRET,
+            image_name.to_string(),
+            "beach".to_string(),
+            "clip".to_string(),
+            0.3,
+        )
+    }
+
+    #[test]
+    fn decode_judgements_counts_an_image_once() {
+        let tokens = vec![token("a.jpg"), token("b.jpg"), token("a.jpg")];
+        let judgements = decode_judgements(tokens).unwrap();
+        let names = judgements
+            .iter()
+            .map(|j| j.claims.image_name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["a.jpg", "b.jpg"]);
+    }
+
+    #[test]
+    fn decode_judgements_rejects_an_invalid_token() {
+        assert!(matches!(
+            decode_judgements(vec!["not a token".to_string()]),
+            Err(ApiError::InvalidToken(_))
+        ));
+    }
+}
@@ -3,8 +3,9 @@ use std::ops::Deref;
 use super::Repo;
 use anyhow::Result;
 use chrono::NaiveDateTime;
+use serde::Serialize;
 
-#[derive(Debug, sqlx::FromRow, Default)]
+#[derive(Debug, sqlx::FromRow, Default, Serialize)]
 pub struct Feedback {
     pub id: i32,
     pub text: String,
@@ -14,6 +15,8 @@ pub struct Feedback {
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
     pub deleted_at: Option<NaiveDateTime>,
+    // Set for the judgements of refine-search, see `Repo::create_judgement`
+    pub judgement_token: Option<String>,
 }
 
 impl Repo {
@@ -41,4 +44,73 @@ impl Repo {
 
         Ok(saved_feedback.id)
     }
+
+    // Feedback of a refine-search judgement, recorded once per judged token: the next
+    // refinements of the search send the same tokens again. `None` when already recorded.
+    pub async fn create_judgement(
+        &self,
+        text: String,
+        image_name: String,
+        model: String,
+        feedback: i32,
+        token: String,
+    ) -> Result<Option<i32>> {
+        let client = self.db_pool.deref();
+        let id = sqlx::query_scalar!(
+            r#"
+            INSERT INTO feedback (text, image_name, model, user_feedback, judgement_token)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (md5(judgement_token)) WHERE judgement_token IS NOT NULL DO NOTHING
+            RETURNING id"#,
+            text,
+            image_name,
+            model,
+            feedback,
+            token,
+        )
+        .fetch_optional(client.deref())
+        .await?;
+
+        Ok(id)
+    }
+
+    // Newest first, soft deleted feedback is left out
+    pub async fn list_feedback(
+        &self,
+        image_name: Option<String>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Feedback>> {
+        let client = self.db_pool.deref();
+        let feedback = sqlx::query_as!(
+            Feedback,
+            r#"
+            SELECT * FROM feedback
+            WHERE deleted_at IS NULL AND ($1::TEXT IS NULL OR image_name = $1)
+            ORDER BY id DESC
+            LIMIT $2 OFFSET $3"#,
+            image_name,
+            limit,
+            offset,
+        )
+        .fetch_all(client.deref())
+        .await?;
+
+        Ok(feedback)
+    }
+
+    // Soft delete, `false` if there is no such feedback or it is already deleted
+    pub async fn delete_feedback(&self, id: i32) -> Result<bool> {
+        let client = self.db_pool.deref();
+        let result = sqlx::query!(
+            r#"
+            UPDATE feedback SET deleted_at = NOW()
+            WHERE id = $1 AND deleted_at IS NULL"#,
+            id,
+        )
+        .execute(client.deref())
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
 }
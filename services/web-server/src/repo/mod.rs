@@ -1,15 +1,17 @@
-
-use xlib::client::PostgresClient;
 use std::sync::Arc;
+use xlib::client::PostgresClient;
 
 mod feedback;
+mod zero_result_query;
+
+pub use feedback::Feedback;
+
 pub struct Repo {
     db_pool: Arc<PostgresClient>,
 }
 
 impl Repo {
-    pub fn new(db_pool: Arc<PostgresClient>) -> Self {
+    pub const fn new(db_pool: Arc<PostgresClient>) -> Self {
         Self { db_pool }
     }
 }
-
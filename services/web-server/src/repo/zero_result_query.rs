@@ -0,0 +1,58 @@
+use std::ops::Deref;
+
+use super::Repo;
+use anyhow::Result;
+use chrono::NaiveDateTime;
+
+// A search that had no match good enough to be returned, kept to find the gaps of the catalogue
+#[allow(dead_code)]
+#[derive(Debug, sqlx::FromRow, Default)]
+pub struct ZeroResultQuery {
+    pub id: i32,
+    pub text: String,
+    pub min_score: Option<f32>,
+    pub created_at: NaiveDateTime,
+}
+
+impl Repo {
+    pub async fn create_zero_result_query(
+        &self,
+        text: String,
+        min_score: Option<f32>,
+    ) -> Result<i32> {
+        let client = self.db_pool.deref();
+        let saved_query = sqlx::query_as!(
+            ZeroResultQuery,
+            r#"
+            INSERT INTO zero_result_query (text, min_score)
+            VALUES ($1, $2)
+            RETURNING *"#,
+            text,
+            min_score,
+        )
+        .fetch_one(client.deref())
+        .await?;
+
+        Ok(saved_query.id)
+    }
+
+    // Same as `create_zero_result_query` for many queries in one statement
+    pub async fn create_zero_result_queries(
+        &self,
+        texts: Vec<String>,
+        min_scores: Vec<Option<f32>>,
+    ) -> Result<u64> {
+        let client = self.db_pool.deref();
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO zero_result_query (text, min_score)
+            SELECT * FROM UNNEST($1::TEXT[], $2::REAL[])"#,
+            &texts,
+            &min_scores as &[Option<f32>],
+        )
+        .execute(client.deref())
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+}
@@ -0,0 +1,395 @@
+use std::io::Cursor;
+
+use axum::extract::{Json, State};
+use base64::{prelude::BASE64_STANDARD, Engine};
+use image::ImageReader;
+use qdrant_client::qdrant::{Condition, Filter, QueryPointsBuilder, ScoredPoint};
+use serde::{Deserialize, Serialize};
+use xlib::app::metrics::observe;
+
+use crate::{
+    create_jwt,
+    embedding_cache::EmbeddingCache,
+    error::ApiError,
+    extract::ApiJson,
+    filter::SearchFilter,
+    hybrid,
+    metadata::ImageMetadata,
+    mmr, points,
+    query::{self, ImageReference, QueryError, WeightedTerm, DEFAULT_IMAGE_WEIGHT},
+    repo, AppState, JWT_SECRET,
+};
+
+pub const DEFAULT_SEARCH_LIMIT: u64 = 1;
+pub const MAX_SEARCH_LIMIT: u64 = 100;
+
+#[derive(Deserialize)]
+pub struct SearchImageRequest {
+    #[serde(default)]
+    text: String,
+    // Weighted text terms, combined with `text` if both are given
+    #[serde(default)]
+    terms: Vec<WeightedTerm>,
+    // Reference image, fused with the text terms
+    image: Option<ImageReference>,
+    // Share of the reference image in the query vector, between 0 and 1
+    image_weight: Option<f32>,
+    limit: Option<u64>,
+    filter: Option<SearchFilter>,
+    // Excludes the images flagged as unsafe, on by default
+    safe_search: Option<bool>,
+    // Fuses the vector matches with keyword matches on the image names, folders and tags
+    #[serde(default)]
+    hybrid: bool,
+    // Re-ranks the matches with maximal marginal relevance, between 0 (most diverse) and 1
+    mmr_lambda: Option<f32>,
+    // Vector matches with a lower cosine similarity are left out, overrides the server default
+    min_score: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct SearchImageResponse {
+    pub text: String,
+    model_name: String,
+    pub matches: Vec<ImageMatch>,
+    // Token of the best match, `null` when nothing matched
+    jwt: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ImageMatch {
+    image_name: String,
+    score: f32,
+    metadata: ImageMetadata,
+    jwt: String,
+}
+
+pub async fn search_image_handler(
+    State(state): State<AppState>,
+    ApiJson(payload): ApiJson<SearchImageRequest>,
+) -> Result<Json<SearchImageResponse>, ApiError> {
+    let search = PreparedSearch::new(&state, payload).await?;
+    let min_score = search.min_score;
+    let response = search.run(&state).await?;
+    // recorded in the background, the response doesn't wait for the insert
+    if response.matches.is_empty() {
+        tokio::spawn(record_zero_result(state, response.text.clone(), min_score));
+    }
+    Ok(Json(response))
+}
+
+// Queries without any good enough match are kept to find what the catalogue is missing
+pub async fn record_zero_result(state: AppState, query_text: String, min_score: Option<f32>) {
+    tracing::info!(
+        "Zero-result query: {:?} (min score {:?})",
+        query_text,
+        min_score
+    );
+    let repo = repo::Repo::new(state.pg_client);
+    if let Err(e) = repo.create_zero_result_query(query_text, min_score).await {
+        tracing::error!("Failed to record the zero-result query: {}", e);
+    }
+}
+
+// Same as `record_zero_result` for the queries of a batch, in one statement
+pub async fn record_zero_results(state: AppState, queries: Vec<(String, Option<f32>)>) {
+    for (query_text, min_score) in &queries {
+        tracing::info!(
+            "Zero-result query: {:?} (min score {:?})",
+            query_text,
+            min_score
+        );
+    }
+    let (texts, min_scores) = queries.into_iter().unzip();
+    let repo = repo::Repo::new(state.pg_client);
+    if let Err(e) = repo.create_zero_result_queries(texts, min_scores).await {
+        tracing::error!("Failed to record the zero-result queries: {}", e);
+    }
+}
+
+// Query vector and filter of a search request, ready to be sent to Qdrant
+pub struct PreparedSearch {
+    pub query_text: String,
+    pub query_vector: Vec<f32>,
+    pub filter: Option<Filter>,
+    pub limit: u64,
+    // Keywords of the lexical search, empty unless the search is hybrid
+    pub lexical_tokens: Vec<String>,
+    pub mmr_lambda: Option<f32>,
+    pub min_score: Option<f32>,
+}
+
+impl PreparedSearch {
+    pub async fn new(state: &AppState, payload: SearchImageRequest) -> Result<Self, ApiError> {
+        // `text` is a shorthand for a single term with a weight of 1
+        let mut terms = payload.terms;
+        if !payload.text.trim().is_empty() {
+            terms.insert(
+                0,
+                WeightedTerm {
+                    text: payload.text,
+                    weight: 1.0,
+                },
+            );
+        }
+        let image_weight = payload.image_weight.unwrap_or(DEFAULT_IMAGE_WEIGHT);
+        query::validate(&terms, payload.image.as_ref(), image_weight)?;
+        if payload
+            .mmr_lambda
+            .is_some_and(|lambda| !(0.0..=1.0).contains(&lambda))
+        {
+            return Err(QueryError::InvalidMmrLambda.into());
+        }
+        let min_score = payload.min_score.or(state.config.search.min_score);
+        if min_score.is_some_and(|score| !score.is_finite()) {
+            return Err(QueryError::InvalidMinScore.into());
+        }
+        let query_text = query::describe(&terms, payload.image.as_ref());
+        let lexical_tokens = if payload.hybrid {
+            let texts = terms
+                .iter()
+                .filter(|term| term.weight > 0.0)
+                .map(|term| term.text.as_str())
+                .collect::<Vec<_>>();
+            hybrid::tokenize(&texts.join(" "))
+        } else {
+            Vec::new()
+        };
+        if lexical_tokens.len() > hybrid::MAX_LEXICAL_TOKENS {
+            return Err(QueryError::TooManyTokens.into());
+        }
+
+        let text_vector = text_vector(&state.embedding_cache, &terms).await?;
+        let image_vector = match &payload.image {
+            Some(image) => Some(image_vector(state, image).await?),
+            None => None,
+        };
+        let query_vector = match (image_vector, text_vector) {
+            (Some(image_vector), Some(text_vector)) => query::combine([
+                (image_vector.as_slice(), image_weight),
+                (text_vector.as_slice(), 1.0 - image_weight),
+            ])?,
+            (Some(vector), None) | (None, Some(vector)) => vector,
+            (None, None) => return Err(QueryError::Empty.into()),
+        };
+
+        let filter = payload
+            .filter
+            .unwrap_or_default()
+            .to_qdrant_filter(payload.safe_search.unwrap_or(true))?;
+        let mut search = Self {
+            query_text,
+            query_vector,
+            filter,
+            limit: payload
+                .limit
+                .unwrap_or(DEFAULT_SEARCH_LIMIT)
+                .clamp(1, MAX_SEARCH_LIMIT),
+            lexical_tokens,
+            mmr_lambda: payload.mmr_lambda,
+            min_score,
+        };
+        if let Some(ImageReference::ImageName(image_name)) = &payload.image {
+            // the reference image would otherwise always be the best match
+            search.exclude([image_name.as_str()]);
+        }
+
+        Ok(search)
+    }
+
+    // Leave the given images out of the matches
+    pub fn exclude<'a>(&mut self, image_names: impl IntoIterator<Item = &'a str>) {
+        let ids = image_names
+            .into_iter()
+            .map(points::point_id)
+            .collect::<Vec<_>>();
+        if !ids.is_empty() {
+            self.filter
+                .get_or_insert_with(Filter::default)
+                .must_not
+                .push(Condition::has_id(ids));
+        }
+    }
+
+    pub async fn run(self, state: &AppState) -> Result<SearchImageResponse, ApiError> {
+        let collection_name = state.config.qdrant.collection.as_str();
+        let is_hybrid = !self.lexical_tokens.is_empty();
+        let is_diversified = self.mmr_lambda.is_some();
+        // hybrid and diversified searches pick the matches from a larger candidate set
+        let candidates = match (is_hybrid, is_diversified) {
+            (false, false) => self.limit,
+            (true, false) => self.limit.max(hybrid::HYBRID_CANDIDATES),
+            (_, true) => self.limit.max(mmr::MMR_CANDIDATES),
+        };
+        let mut query = QueryPointsBuilder::new(collection_name)
+            .query(self.query_vector)
+            .limit(candidates)
+            .with_payload(true)
+            .with_vectors(is_diversified);
+        // only the vector leg is thresholded, the lexical matches of a hybrid search are kept
+        // whatever their cosine similarity
+        if let Some(min_score) = self.min_score {
+            query = query.score_threshold(min_score);
+        }
+        if let Some(filter) = self.filter.clone() {
+            query = query.filter(filter);
+        }
+
+        let mut points = observe("qdrant", "query", state.qdrant_client.query(query))
+            .await?
+            .result;
+        if is_hybrid {
+            let lexical_points = hybrid::lexical_search(
+                &state.qdrant_client,
+                collection_name,
+                self.filter,
+                &self.lexical_tokens,
+                is_diversified,
+            )
+            .await?;
+            points = hybrid::reciprocal_rank_fusion([points, lexical_points], candidates);
+        }
+        match self.mmr_lambda {
+            Some(lambda) => points = mmr::rerank(points, lambda, self.limit),
+            None => points.truncate(usize::try_from(self.limit).unwrap_or(usize::MAX)),
+        }
+
+        build_response(self.query_text, points)
+    }
+}
+
+// Combined vector of the text terms, `None` if the query has no text
+async fn text_vector(
+    embedding_cache: &EmbeddingCache,
+    terms: &[WeightedTerm],
+) -> Result<Option<Vec<f32>>, ApiError> {
+    if terms.is_empty() {
+        return Ok(None);
+    }
+
+    // one call for all the terms, `query::validate` caps their number
+    let texts = terms
+        .iter()
+        .map(|term| term.text.clone())
+        .collect::<Vec<_>>();
+    let term_vectors = embedding_cache.texts_to_vectors(&texts).await?;
+
+    Ok(Some(query::combine(
+        term_vectors
+            .iter()
+            .map(Vec::as_slice)
+            .zip(terms.iter().map(|term| term.weight)),
+    )?))
+}
+
+async fn image_vector(state: &AppState, image: &ImageReference) -> Result<Vec<f32>, ApiError> {
+    match image {
+        ImageReference::ImageName(image_name) => {
+            let mut vectors = points::fetch_vectors(
+                &state.qdrant_client,
+                &state.config.qdrant.collection,
+                std::slice::from_ref(image_name),
+            )
+            .await?;
+            vectors
+                .remove(image_name)
+                .ok_or_else(|| ApiError::ImageNotIndexed(image_name.clone()))
+        }
+        ImageReference::Base64(image_base64) => {
+            check_image(image_base64, state.config.search.max_image_bytes)?;
+            Ok(state.clip_client.image_to_vector(image_base64).await?)
+        }
+    }
+}
+
+// An upload the CLIP model can't read is the caller's mistake rather than an upstream failure.
+// Only the header is read, the CLIP model decodes the image itself.
+fn check_image(image_base64: &str, max_bytes: usize) -> Result<(), ApiError> {
+    let bytes = BASE64_STANDARD
+        .decode(image_base64)
+        .map_err(|e| ApiError::InvalidImage(format!("not base64: {e}")))?;
+    if bytes.len() > max_bytes {
+        return Err(ApiError::InvalidImage(format!(
+            "larger than {max_bytes} bytes"
+        )));
+    }
+    ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(|e| ApiError::InvalidImage(e.to_string()))?
+        .into_dimensions()
+        .map_err(|e| ApiError::InvalidImage(e.to_string()))?;
+    Ok(())
+}
+
+pub fn build_response(
+    query_text: String,
+    points: Vec<ScoredPoint>,
+) -> Result<SearchImageResponse, ApiError> {
+    let mut matches = Vec::with_capacity(points.len());
+    for point in points {
+        let Some(image_name) = point
+            .payload
+            .get("image_name")
+            .and_then(|v| v.as_str())
+            .cloned()
+        else {
+            return Err(ApiError::Internal(format!(
+                "point {:?} has no image name",
+                point.id
+            )));
+        };
+        let score = point.score;
+        let jwt = create_jwt(
+            JWT_SECRET,
+            image_name.clone(),
+            query_text.clone(),
+            "CLIP".to_string(),
+            score,
+        );
+        matches.push(ImageMatch {
+            image_name,
+            score,
+            metadata: ImageMetadata::from_payload(point.payload),
+            jwt,
+        });
+    }
+
+    Ok(SearchImageResponse {
+        text: query_text,
+        model_name: "CLIP".to_string(),
+        // the top level token is kept for clients that only look at the best match
+        jwt: matches.first().map(|m| m.jwt.clone()),
+        matches,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_base64() -> String {
+        let mut png = Vec::new();
+        image::RgbImage::new(2, 2)
+            .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        BASE64_STANDARD.encode(png)
+    }
+
+    #[test]
+    fn uploaded_image_is_checked() {
+        let image = png_base64();
+        assert!(check_image(&image, 1024).is_ok());
+        assert!(matches!(
+            check_image(&image, 16),
+            Err(ApiError::InvalidImage(_))
+        ));
+        assert!(matches!(
+            check_image("not base64!", 1024),
+            Err(ApiError::InvalidImage(_))
+        ));
+        assert!(matches!(
+            check_image(&BASE64_STANDARD.encode("plain text"), 1024),
+            Err(ApiError::InvalidImage(_))
+        ));
+    }
+}
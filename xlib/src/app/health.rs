@@ -0,0 +1,102 @@
+use std::{collections::BTreeMap, fmt::Display, future::Future, time::Duration, time::Instant};
+
+use anyhow::{bail, Context, Result};
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use qdrant_client::{qdrant::vectors_config::Config as VectorsConfig, Qdrant};
+use serde::Serialize;
+
+// Liveness only tells that the process answers, an orchestrator restarts the service when it
+// doesn't. It never checks the dependencies: restarting doesn't fix them.
+pub async fn liveness() -> Json<Liveness> {
+    Json(Liveness { status: "alive" })
+}
+
+#[derive(Serialize)]
+pub struct Liveness {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct DependencyHealth {
+    healthy: bool,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// Runs the health check of a dependency, a check still running after `timeout` fails
+pub async fn probe<F, E>(timeout: Duration, check: F) -> DependencyHealth
+where
+    F: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    let start = Instant::now();
+    let error = match tokio::time::timeout(timeout, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(format!("{e:#}")),
+        Err(_) => Some(format!("no answer within {timeout:?}")),
+    };
+
+    DependencyHealth {
+        healthy: error.is_none(),
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        error,
+    }
+}
+
+// The image collection exists and holds vectors of the size of the CLIP model, a probe of both
+// services
+pub async fn check_collection(client: &Qdrant, collection: &str, vector_size: u64) -> Result<()> {
+    let info = client
+        .collection_info(collection)
+        .await?
+        .result
+        .with_context(|| format!("no info on collection {collection}"))?;
+    let vectors_config = info
+        .config
+        .and_then(|config| config.params)
+        .and_then(|params| params.vectors_config)
+        .and_then(|vectors_config| vectors_config.config);
+
+    match vectors_config {
+        Some(VectorsConfig::Params(params)) if params.size == vector_size => Ok(()),
+        Some(VectorsConfig::Params(params)) => bail!(
+            "collection {collection} holds vectors of size {}, expected {vector_size}",
+            params.size
+        ),
+        _ => bail!("collection {collection} doesn't hold a single unnamed vector"),
+    }
+}
+
+// Health of every dependency, answered with 503 unless all of them are healthy so that the
+// service is taken out of the load balancer until then
+#[derive(Serialize)]
+pub struct Readiness {
+    ready: bool,
+    dependencies: BTreeMap<&'static str, DependencyHealth>,
+}
+
+impl FromIterator<(&'static str, DependencyHealth)> for Readiness {
+    fn from_iter<I: IntoIterator<Item = (&'static str, DependencyHealth)>>(iter: I) -> Self {
+        let dependencies = iter.into_iter().collect::<BTreeMap<_, _>>();
+        Self {
+            ready: dependencies.values().all(|health| health.healthy),
+            dependencies,
+        }
+    }
+}
+
+impl IntoResponse for Readiness {
+    fn into_response(self) -> Response {
+        let status = if self.ready {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (status, Json(self)).into_response()
+    }
+}
@@ -0,0 +1,106 @@
+use std::{future::Future, time::Duration, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    middleware::{self, Next},
+    response::Response,
+    routing::get,
+    Router,
+};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use tracing::Instrument;
+
+// Latency buckets (seconds) of every `*_duration_seconds` histogram, from a cache hit to a slow
+// embedding on CPU
+const DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+// Histograms are drained on upkeep, keeps their memory bounded between two scrapes
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+// Installs the global Prometheus recorder, the `metrics` macros record into it from then on.
+// Call it once, before the first metric is recorded.
+pub fn setup() -> PrometheusHandle {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("_duration_seconds".to_string()),
+            DURATION_BUCKETS,
+        )
+        .expect("the duration buckets are not empty")
+        .install_recorder()
+        .expect("a metrics recorder is already installed");
+
+    let upkeep_handle = handle.clone();
+    std::thread::spawn(move || loop {
+        std::thread::sleep(UPKEEP_INTERVAL);
+        upkeep_handle.run_upkeep();
+    });
+
+    handle
+}
+
+// `GET /metrics` in the Prometheus text format
+pub fn router(handle: PrometheusHandle) -> Router {
+    Router::new().route("/metrics", get(move || async move { handle.render() }))
+}
+
+// Counts the requests and their latency per route, `http_requests_total` and
+// `http_request_duration_seconds` labeled by method, route and status
+pub fn track_requests<S>(router: Router<S>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    router.route_layer(middleware::from_fn(record_request))
+}
+
+async fn record_request(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    // the route template, e.g. `/admin/v1/feedback/{id}`, keeps the number of series bounded
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(String::new, |path| path.as_str().to_string());
+    let method = request.method().to_string();
+
+    let response = next.run(request).await;
+
+    let labels = [
+        ("method", method),
+        ("route", route),
+        ("status", response.status().as_u16().to_string()),
+    ];
+    metrics::counter!("http_requests_total", &labels).increment(1);
+    metrics::histogram!("http_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+    response
+}
+
+// Times a call to a dependency, `upstream_request_duration_seconds` and
+// `upstream_request_errors_total` labeled by dependency and operation, e.g. `qdrant` and `query`.
+// The call runs in a client span of the trace.
+pub async fn observe<T, E, F>(
+    dependency: &'static str,
+    operation: &'static str,
+    call: F,
+) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+{
+    let span = tracing::info_span!(
+        "upstream",
+        dependency,
+        operation,
+        otel.kind = "client",
+        otel.name = format!("{dependency} {operation}"),
+    );
+    let start = Instant::now();
+    let result = call.instrument(span).await;
+
+    let labels = [("dependency", dependency), ("operation", operation)];
+    metrics::histogram!("upstream_request_duration_seconds", &labels)
+        .record(start.elapsed().as_secs_f64());
+    if result.is_err() {
+        metrics::counter!("upstream_request_errors_total", &labels).increment(1);
+    }
+    result
+}
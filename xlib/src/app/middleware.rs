@@ -0,0 +1,65 @@
+use std::time::Instant;
+
+use axum::{
+    body::HttpBody,
+    extract::{MatchedPath, Request},
+    http::header,
+    middleware::{self, Next},
+    response::Response,
+    Router,
+};
+use tower_http::{
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+
+use crate::app::tracing::{request_span, ACCESS_LOG_TARGET};
+
+// The request ID is taken from the `x-request-id` header or generated, recorded on the span of
+// the request so that every log line of the request carries it, and sent back. Every request
+// is written to the JSON access log once answered.
+pub fn with_request_tracing(router: Router) -> Router {
+    router
+        .layer(middleware::from_fn(access_log))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(TraceLayer::new_for_http().make_span_with(request_span))
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+async fn access_log(request: Request, next: Next) -> Response {
+    let start = Instant::now();
+    let method = request.method().clone();
+    // the route is empty when none matched, e.g. on a 404
+    let path = request.uri().path().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(String::new, |path| path.as_str().to_string());
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+
+    let response = next.run(request).await;
+
+    // unknown for streamed bodies
+    let bytes = response.body().size_hint().exact().or_else(|| {
+        response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+    });
+    tracing::info!(
+        target: ACCESS_LOG_TARGET,
+        method = %method,
+        route,
+        path,
+        status = response.status().as_u16(),
+        latency_ms = start.elapsed().as_secs_f64() * 1000.0,
+        bytes,
+        request_id,
+    );
+    response
+}
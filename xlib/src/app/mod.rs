@@ -1,3 +1,7 @@
 pub mod graceful_shutdown;
+pub mod health;
+pub mod metrics;
+pub mod middleware;
+pub mod problem;
 pub mod serve;
 pub mod tracing;
@@ -1,10 +1,192 @@
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use anyhow::{Context, Result};
+use axum::extract::MatchedPath;
+use http::{HeaderMap, HeaderName, HeaderValue, Request};
+use opentelemetry::{propagation::Extractor, trace::TracerProvider as _, KeyValue};
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator, runtime, trace::TracerProvider, Resource,
+};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    filter::filter_fn, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Layer,
+    Registry,
+};
 
-pub fn setup() {
-    let fmt_layer = tracing_subscriber::fmt::layer().with_line_number(true);
+// Spans are exported over OTLP/gRPC when it is set, e.g. `http://otel-collector:4317`
+const OTLP_ENDPOINT_ENV: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+// Overrides the service name passed to `setup`
+const SERVICE_NAME_ENV: &str = "OTEL_SERVICE_NAME";
+// Events of this target are written as JSON lines, one per request, see `middleware`
+pub const ACCESS_LOG_TARGET: &str = "access_log";
+
+// Logs to stdout, and exports spans over OTLP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set.
+// `RUST_LOG` overrides `default_directives`. Call it from within the Tokio runtime, the exporter
+// runs on it.
+pub fn setup(service_name: &str, default_directives: &str) -> TracingGuard {
+    setup_reloadable(service_name, default_directives).1
+}
+
+// Handle on the log filter of a running service, cheap to clone
+#[derive(Clone)]
+pub struct LogFilter(reload::Handle<EnvFilter, Registry>);
+
+impl LogFilter {
+    pub fn current(&self) -> Result<String> {
+        self.0
+            .with_current(ToString::to_string)
+            .context("the subscriber is gone")
+    }
+
+    // `directives` has the syntax of `RUST_LOG`, e.g. `debug,hyper=off`
+    pub fn set(&self, directives: &str) -> Result<()> {
+        let filter = EnvFilter::try_new(directives)
+            .with_context(|| format!("invalid log filter `{directives}`"))?;
+        self.0.reload(filter).context("the subscriber is gone")
+    }
+}
+
+// Keeps the OTLP exporter running, `shutdown` exports the spans still buffered
+#[must_use]
+pub struct TracingGuard(Option<TracerProvider>);
+
+impl TracingGuard {
+    pub async fn shutdown(self) {
+        let Some(provider) = self.0 else {
+            return;
+        };
+        // blocks until the batch task, which runs on the runtime, is done
+        let result = tokio::task::spawn_blocking(move || provider.shutdown()).await;
+        if let Ok(Err(e)) = result {
+            tracing::warn!("Failed to export the last spans: {}", e);
+        }
+    }
+}
+
+// Same as `setup` with a filter that can be changed at runtime
+pub fn setup_reloadable(service_name: &str, default_directives: &str) -> (LogFilter, TracingGuard) {
+    let fmt_layer = tracing_subscriber::fmt::layer()
+        .with_line_number(true)
+        .with_filter(filter_fn(|metadata| metadata.target() != ACCESS_LOG_TARGET));
+    let access_log_layer = tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_span_list(false)
+        .with_filter(filter_fn(|metadata| metadata.target() == ACCESS_LOG_TARGET));
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| default_directives.into());
+    let (filter_layer, handle) = reload::Layer::new(filter);
+    // logged once the subscriber is set up
+    let (provider, exporter_error) = match otlp_provider(service_name) {
+        Ok(provider) => (provider, None),
+        Err(e) => (None, Some(e)),
+    };
+    let otel_layer = provider.as_ref().map(|provider| {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name.to_string()))
+    });
 
     tracing_subscriber::registry()
-        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| "debug,hyper=off".into()))
+        .with(filter_layer)
         .with(fmt_layer)
+        .with(access_log_layer)
+        .with(otel_layer)
         .init();
+
+    if let Some(e) = exporter_error {
+        tracing::warn!("{:#}, spans won't be exported", e);
+    }
+    if let (Some(_), Some(endpoint)) = (&provider, otlp_endpoint()) {
+        tracing::info!("Exporting spans to {}", endpoint);
+    }
+    (LogFilter(handle), TracingGuard(provider))
+}
+
+fn otlp_endpoint() -> Option<String> {
+    std::env::var(OTLP_ENDPOINT_ENV)
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())
+}
+
+// `None` without an endpoint
+fn otlp_provider(service_name: &str) -> Result<Option<TracerProvider>> {
+    if otlp_endpoint().is_none() {
+        return Ok(None);
+    }
+    // the exporter reads the endpoint from the environment as well
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .build()
+        .context("failed to set up the OTLP exporter")?;
+    let service_name = std::env::var(SERVICE_NAME_ENV).unwrap_or_else(|_| service_name.to_string());
+    let provider = TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::Tokio)
+        .with_resource(Resource::default().merge(&Resource::new([KeyValue::new(
+            "service.name",
+            service_name,
+        )])))
+        .build();
+
+    // W3C `traceparent` and `tracestate` headers
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    Ok(Some(provider))
+}
+
+// Span of an incoming HTTP request, continues the trace of the caller when the request carries
+// a `traceparent` header
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    // the route template keeps the span names few, e.g. `GET /admin/v1/feedback/{id}`
+    let name = request.extensions().get::<MatchedPath>().map_or_else(
+        || request.method().to_string(),
+        |path| format!("{} {}", request.method(), path.as_str()),
+    );
+    let request_id = request
+        .headers()
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id,
+        otel.kind = "server",
+        otel.name = name,
+    );
+
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(request.headers()))
+    });
+    span.set_parent(parent);
+    span
+}
+
+// Trace context of the current span, to send along with an outgoing HTTP request. Empty when
+// the spans are not exported.
+pub fn trace_context_headers() -> HeaderMap {
+    let context = Span::current().context();
+    let mut fields = std::collections::HashMap::new();
+    opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&context, &mut fields);
+    });
+
+    fields
+        .into_iter()
+        .filter_map(|(name, value)| {
+            Some((
+                HeaderName::try_from(name).ok()?,
+                HeaderValue::try_from(value).ok()?,
+            ))
+        })
+        .collect()
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
 }
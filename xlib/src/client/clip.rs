@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::app::{metrics::observe, tracing::trace_context_headers};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClipError {
+    #[error("failed to call CLIP model: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("CLIP model responded with status {0}")]
+    Status(reqwest::StatusCode),
+    // The vectors are matched to the texts by position, a missing one would shift the others
+    #[error("CLIP model returned {actual} vectors for {expected} texts")]
+    VectorCount { expected: usize, actual: usize },
+}
+
+impl ClipError {
+    // The CLIP model refused the input itself, sending it again gets the same answer. A transport
+    // error, a 5xx or a 429 can pass on the next attempt.
+    pub fn is_rejection(&self) -> bool {
+        matches!(self, Self::Status(status)
+            if status.is_client_error() && *status != reqwest::StatusCode::TOO_MANY_REQUESTS)
+    }
+}
+
+pub struct ClipClientConfig {
+    pub url: String,
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    // Idle connections kept open to the CLIP model, reqwest's default (no limit) when not set
+    pub pool_max_idle_per_host: Option<usize>,
+    // Texts sent to the CLIP model in one request, 64 when not set
+    pub batch_size: Option<usize>,
+}
+
+const DEFAULT_BATCH_SIZE: usize = 64;
+
+#[derive(Deserialize)]
+struct VectorResponse {
+    vector: Vec<f32>,
+}
+
+#[derive(Deserialize)]
+struct VectorsResponse {
+    vectors: Vec<Vec<f32>>,
+}
+
+// Client of the CLIP model service, cheap to clone: the clones share one connection pool
+#[derive(Clone)]
+pub struct ClipClient {
+    http: reqwest::Client,
+    base_url: String,
+    batch_size: usize,
+}
+
+impl ClipClient {
+    pub fn new(config: &ClipClientConfig) -> Result<Self, ClipError> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(config.timeout)
+            .connect_timeout(config.connect_timeout);
+        if let Some(pool_max_idle_per_host) = config.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(pool_max_idle_per_host);
+        }
+
+        Ok(Self {
+            http: builder.build()?,
+            base_url: config.url.trim_end_matches('/').to_string(),
+            batch_size: config.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1),
+        })
+    }
+
+    pub async fn health_check(&self) -> Result<(), ClipError> {
+        let response = self.http.get(self.url("health")).send().await?;
+        if !response.status().is_success() {
+            return Err(ClipError::Status(response.status()));
+        }
+        Ok(())
+    }
+
+    // Vectors of the texts in the same order, embedded by batches of `batch_size`
+    pub async fn texts_to_vectors(&self, texts: &[String]) -> Result<Vec<Vec<f32>>, ClipError> {
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(self.batch_size) {
+            let response: VectorsResponse = self
+                .post("texts-to-vectors", serde_json::json!({ "texts": batch }))
+                .await?;
+            if response.vectors.len() != batch.len() {
+                return Err(ClipError::VectorCount {
+                    expected: batch.len(),
+                    actual: response.vectors.len(),
+                });
+            }
+            vectors.extend(response.vectors);
+        }
+
+        Ok(vectors)
+    }
+
+    pub async fn image_to_vector(&self, image_base64: &str) -> Result<Vec<f32>, ClipError> {
+        let response: VectorResponse = self
+            .post(
+                "image-to-vector",
+                serde_json::json!({ "image_base64": image_base64 }),
+            )
+            .await?;
+        Ok(response.vector)
+    }
+
+    // Latency and errors are recorded per endpoint
+    async fn post<T: DeserializeOwned>(
+        &self,
+        endpoint: &'static str,
+        body: serde_json::Value,
+    ) -> Result<T, ClipError> {
+        observe("clip", endpoint, async {
+            let response = self
+                .http
+                .post(self.url(endpoint))
+                .headers(trace_context_headers())
+                .json(&body)
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(ClipError::Status(response.status()));
+            }
+            Ok(response.json::<T>().await?)
+        })
+        .await
+    }
+
+    fn url(&self, endpoint: &str) -> String {
+        format!("{}/api/v1/clip/{}", self.base_url, endpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    #[test]
+    fn only_a_client_error_is_a_rejection() {
+        assert!(ClipError::Status(StatusCode::UNPROCESSABLE_ENTITY).is_rejection());
+        assert!(!ClipError::Status(StatusCode::TOO_MANY_REQUESTS).is_rejection());
+        assert!(!ClipError::Status(StatusCode::SERVICE_UNAVAILABLE).is_rejection());
+        let vector_count = ClipError::VectorCount {
+            expected: 2,
+            actual: 1,
+        };
+        assert!(!vector_count.is_rejection());
+    }
+}
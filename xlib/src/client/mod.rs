@@ -1,3 +1,6 @@
+mod clip;
 mod postgres;
+mod redis;
+pub use self::redis::{RedisClient, RedisClientConfig};
+pub use clip::{ClipClient, ClipClientConfig, ClipError};
 pub use postgres::{PostgresClient, PostgresClientConfig};
-
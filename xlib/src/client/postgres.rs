@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use anyhow::{Context, Result};
 use derive_more::{Deref, From, Into};
 use sqlx::{
@@ -15,14 +17,25 @@ pub struct PostgresClientConfig {
     pub user: Option<String>,
     pub password: Option<String>,
     pub db_name: String,
+    // Size of the connection pool, 5 when not set
+    pub max_connections: Option<u32>,
+    // How long a query waits for a free connection, sqlx's default (30s) when not set
+    pub acquire_timeout: Option<Duration>,
 }
 
+const DEFAULT_MAX_CONNECTIONS: u32 = 5;
+
 impl PostgresClient {
     pub async fn build(config: &PostgresClientConfig) -> Result<Self> {
         let url = config.build_url();
 
-        let client = PgPoolOptions::new()
-            .max_connections(5)
+        let mut pool_options = PgPoolOptions::new()
+            .max_connections(config.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS));
+        if let Some(acquire_timeout) = config.acquire_timeout {
+            pool_options = pool_options.acquire_timeout(acquire_timeout);
+        }
+
+        let client = pool_options
             .connect(&url)
             .await
             .context(format!("failed to connect to database: {}", url))?;
@@ -55,7 +68,6 @@ impl PostgresClientConfig {
         if let Some(password) = &self.password {
             options = options.password(password);
         }
-        
 
         options.to_url_lossy().to_string()
     }
@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use derive_more::{Deref, DerefMut, From, Into};
+use redis::{
+    aio::{ConnectionManager, ConnectionManagerConfig},
+    Client, ConnectionAddr, ConnectionInfo, RedisConnectionInfo,
+};
+
+// A multiplexed connection that reconnects in the background once it is lost, cheap to clone and
+// safe to share between tasks. A command sent while it is down fails.
+#[derive(Deref, DerefMut, From, Into, Clone)]
+pub struct RedisClient(ConnectionManager);
+
+#[derive(Default)]
+pub struct RedisClientConfig {
+    pub hostname: String,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub password: Option<String>,
+    pub db: Option<i64>,
+    // How long a command waits for its response, redis' default (none) when not set
+    pub response_timeout: Option<Duration>,
+    // How long connecting may take, redis' default (none) when not set
+    pub connection_timeout: Option<Duration>,
+}
+
+impl RedisClient {
+    pub async fn build(config: &RedisClientConfig) -> Result<Self> {
+        let connection_info = config.connection_info();
+        let address = connection_info.addr.to_string();
+
+        let mut manager_config = ConnectionManagerConfig::new();
+        if let Some(response_timeout) = config.response_timeout {
+            manager_config = manager_config.set_response_timeout(response_timeout);
+        }
+        if let Some(connection_timeout) = config.connection_timeout {
+            manager_config = manager_config.set_connection_timeout(connection_timeout);
+        }
+
+        let connection = Client::open(connection_info)
+            .context(format!("invalid redis configuration for {}", address))?
+            .get_connection_manager_with_config(manager_config)
+            .await
+            .context(format!("failed to connect to redis: {}", address))?;
+
+        tracing::info!("redis client connected successfully on {}", address);
+
+        Ok(Self(connection))
+    }
+
+    pub fn into_inner(self) -> ConnectionManager {
+        self.0
+    }
+}
+
+impl RedisClientConfig {
+    const DEFAULT_PORT: u16 = 6379;
+
+    fn connection_info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            addr: ConnectionAddr::Tcp(
+                self.hostname.clone(),
+                self.port.unwrap_or(Self::DEFAULT_PORT),
+            ),
+            redis: RedisConnectionInfo {
+                db: self.db.unwrap_or_default(),
+                username: self.user.clone(),
+                password: self.password.clone(),
+                ..Default::default()
+            },
+        }
+    }
+}
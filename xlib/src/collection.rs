@@ -0,0 +1,63 @@
+use qdrant_client::{
+    qdrant::{vectors::VectorsOptions, CreateFieldIndexCollectionBuilder, FieldType, Vectors},
+    Qdrant, QdrantError,
+};
+
+use crate::app::metrics::observe;
+
+// Payload fields of the image points that get a Qdrant payload index, so they can be filtered
+// on. The img-to-vec worker writes them, the web server searches them.
+pub const PAYLOAD_INDEXES: &[(&str, FieldType)] = &[
+    // metadata
+    ("image_name", FieldType::Keyword),
+    ("folder", FieldType::Keyword),
+    ("width", FieldType::Integer),
+    ("height", FieldType::Integer),
+    ("aspect_ratio", FieldType::Float),
+    ("format", FieldType::Keyword),
+    ("file_size", FieldType::Integer),
+    ("modified_at", FieldType::Datetime),
+    ("captured_at", FieldType::Datetime),
+    ("camera_make", FieldType::Keyword),
+    ("camera_model", FieldType::Keyword),
+    ("gps_latitude", FieldType::Float),
+    ("gps_longitude", FieldType::Float),
+    ("location", FieldType::Geo),
+    ("search_text", FieldType::Text),
+    // auto-tagging
+    ("tags[].label", FieldType::Keyword),
+    ("tags_version", FieldType::Keyword),
+    // content safety
+    ("safety", FieldType::Float),
+    ("safety_flagged", FieldType::Bool),
+    ("safety_version", FieldType::Keyword),
+];
+
+// Creates every index of `PAYLOAD_INDEXES`, the outcome by field. Creating an index that
+// already exists is a no-op in Qdrant.
+pub async fn create_payload_indexes(
+    qdrant_client: &Qdrant,
+    collection_name: &str,
+) -> Vec<(&'static str, Result<(), QdrantError>)> {
+    let mut outcomes = Vec::with_capacity(PAYLOAD_INDEXES.len());
+    for (field, field_type) in PAYLOAD_INDEXES {
+        let request =
+            CreateFieldIndexCollectionBuilder::new(collection_name, *field, *field_type).wait(true);
+        let result = observe(
+            "qdrant",
+            "create_field_index",
+            qdrant_client.create_field_index(request),
+        )
+        .await;
+        outcomes.push((*field, result.map(|_| ())));
+    }
+    outcomes
+}
+
+// The CLIP vector of a point, the collection holds a single unnamed dense vector
+pub fn dense_vector(vectors: Vectors) -> Option<Vec<f32>> {
+    match vectors.vectors_options? {
+        VectorsOptions::Vector(vector) => vector.try_into_dense().ok(),
+        VectorsOptions::Vectors(_) => None,
+    }
+}
@@ -0,0 +1,127 @@
+// Background jobs stored in Postgres: the `job` table is claimed with `FOR UPDATE SKIP LOCKED`
+// so that any number of runners share it. A service using it adds the migration of the `job`
+// table, see `services/img-to-vec-worker/migrations`.
+mod queue;
+mod runner;
+
+use std::fmt;
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use cron::Schedule;
+pub use queue::JobQueue;
+pub use runner::{Runner, RunnerConfig, RunnerHandle, RunningJob};
+
+// A claimed job, handed to the handler of its kind
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub id: i64,
+    pub kind: String,
+    pub key: Option<String>,
+    pub payload: serde_json::Value,
+    // Including the current one
+    pub attempts: i32,
+    pub max_attempts: i32,
+}
+
+impl Job {
+    pub fn payload<T: DeserializeOwned>(&self) -> anyhow::Result<T> {
+        serde_json::from_value(self.payload.clone())
+            .with_context(|| format!("invalid payload of {} job {}", self.kind, self.id))
+    }
+}
+
+// A job that failed for good, kept until it is requeued
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedJob {
+    pub id: i64,
+    pub key: Option<String>,
+    pub attempts: i32,
+    pub error: Option<String>,
+    pub failed_at: DateTime<Utc>,
+}
+
+pub struct NewJob {
+    pub kind: &'static str,
+    pub key: Option<String>,
+    pub payload: serde_json::Value,
+    pub priority: i16,
+    pub max_attempts: i32,
+}
+
+impl NewJob {
+    pub fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            key: None,
+            payload: serde_json::Value::Object(serde_json::Map::new()),
+            priority: 0,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+        }
+    }
+
+    // At most one job of the kind has the key, whatever its status
+    #[must_use]
+    pub fn key(mut self, key: impl Into<String>) -> Self {
+        self.key = Some(key.into());
+        self
+    }
+
+    pub fn payload(mut self, payload: &impl Serialize) -> anyhow::Result<Self> {
+        self.payload = serde_json::to_value(payload).context("failed to serialize job payload")?;
+        Ok(self)
+    }
+
+    // Higher first, 0 by default
+    #[must_use]
+    pub const fn priority(mut self, priority: i16) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    #[must_use]
+    pub const fn max_attempts(mut self, max_attempts: i32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+}
+
+pub const DEFAULT_MAX_ATTEMPTS: i32 = 5;
+
+// Error of a job handler. A job is retried with a backoff until it runs out of attempts, unless
+// the error is permanent: retrying won't help, e.g. the input is invalid.
+#[derive(Debug)]
+pub struct JobError {
+    error: anyhow::Error,
+    permanent: bool,
+}
+
+impl JobError {
+    pub fn permanent(error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            error: error.into(),
+            permanent: true,
+        }
+    }
+
+    pub fn retry(error: impl Into<anyhow::Error>) -> Self {
+        Self {
+            error: error.into(),
+            permanent: false,
+        }
+    }
+}
+
+impl From<anyhow::Error> for JobError {
+    fn from(error: anyhow::Error) -> Self {
+        Self::retry(error)
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.error)
+    }
+}
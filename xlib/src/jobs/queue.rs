@@ -0,0 +1,396 @@
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::{Pool, Postgres, Row};
+
+use super::{FailedJob, Job, NewJob, DEFAULT_MAX_ATTEMPTS};
+
+// Key of the single job of a scheduled kind, re-armed at every fire time
+const SCHEDULE_KEY: &str = "schedule";
+
+// The `job` table. The queries are checked at runtime: the table lives in the database of each
+// service using it.
+#[derive(Clone)]
+pub struct JobQueue {
+    pool: Pool<Postgres>,
+}
+
+impl JobQueue {
+    pub const fn new(pool: Pool<Postgres>) -> Self {
+        Self { pool }
+    }
+
+    // `None` when a job of the kind already has the key
+    pub async fn enqueue(&self, job: NewJob) -> Result<Option<i64>> {
+        sqlx::query_scalar(
+            "INSERT INTO job (kind, key, payload, priority, max_attempts)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (kind, key) DO NOTHING
+            RETURNING id",
+        )
+        .bind(job.kind)
+        .bind(job.key)
+        .bind(job.payload)
+        .bind(job.priority)
+        .bind(job.max_attempts)
+        .fetch_optional(&self.pool)
+        .await
+        .with_context(|| format!("failed to enqueue {} job", job.kind))
+    }
+
+    // Same as `enqueue` for many keyed jobs of one kind, returns how many were new
+    pub async fn enqueue_keys(
+        &self,
+        kind: &str,
+        jobs: Vec<(String, serde_json::Value)>,
+        max_attempts: i32,
+    ) -> Result<u64> {
+        let (keys, payloads): (Vec<_>, Vec<_>) = jobs.into_iter().unzip();
+        let result = sqlx::query(
+            "INSERT INTO job (kind, key, payload, max_attempts)
+            SELECT $1, key, payload, $4 FROM UNNEST($2::TEXT[], $3::JSONB[]) AS t (key, payload)
+            ON CONFLICT (kind, key) DO NOTHING",
+        )
+        .bind(kind)
+        .bind(keys)
+        .bind(payloads)
+        .bind(max_attempts)
+        .execute(&self.pool)
+        .await
+        .with_context(|| format!("failed to enqueue {kind} jobs"))?;
+        Ok(result.rows_affected())
+    }
+
+    // Runs the job again, or enqueues it when there is none with the key. Left alone while it
+    // runs, `false` then.
+    pub async fn requeue(&self, job: NewJob) -> Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO job (kind, key, payload, priority, max_attempts)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (kind, key) DO UPDATE SET
+                payload = EXCLUDED.payload, priority = EXCLUDED.priority,
+                max_attempts = EXCLUDED.max_attempts, status = 'queued', attempts = 0,
+                run_at = now(), locked_until = NULL, last_error = NULL, updated_at = now()
+            WHERE job.status <> 'running'",
+        )
+        .bind(job.kind)
+        .bind(job.key)
+        .bind(job.payload)
+        .bind(job.priority)
+        .bind(job.max_attempts)
+        .execute(&self.pool)
+        .await
+        .with_context(|| format!("failed to requeue {} job", job.kind))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Queues the job of a scheduled kind to run at `run_at`, unless the previous run is still
+    // queued or running
+    pub async fn arm_schedule(&self, kind: &str, run_at: DateTime<Utc>) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO job (kind, key, run_at, max_attempts) VALUES ($1, $2, $3, $4)
+            ON CONFLICT (kind, key) DO UPDATE SET
+                status = 'queued', attempts = 0, run_at = EXCLUDED.run_at, locked_until = NULL,
+                updated_at = now()
+            WHERE job.status IN ('done', 'failed')",
+        )
+        .bind(kind)
+        .bind(SCHEDULE_KEY)
+        .bind(run_at)
+        .bind(DEFAULT_MAX_ATTEMPTS)
+        .execute(&self.pool)
+        .await
+        .with_context(|| format!("failed to schedule {kind} job"))?;
+        Ok(())
+    }
+
+    // Runs a scheduled kind now rather than at its next fire time
+    pub async fn run_schedule_now(&self, kind: &str) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO job (kind, key, max_attempts) VALUES ($1, $2, $3)
+            ON CONFLICT (kind, key) DO UPDATE SET
+                status = 'queued', attempts = 0, run_at = now(), locked_until = NULL,
+                updated_at = now()
+            WHERE job.status <> 'running'",
+        )
+        .bind(kind)
+        .bind(SCHEDULE_KEY)
+        .bind(DEFAULT_MAX_ATTEMPTS)
+        .execute(&self.pool)
+        .await
+        .with_context(|| format!("failed to run {kind} job"))?;
+        Ok(())
+    }
+
+    // Takes the next due job of one of `kinds`, highest priority first. A job still running
+    // after its visibility timeout is taken again, its runner is assumed dead.
+    //
+    // The `attempts` of the claimed job fence the runner holding it: the updates below only
+    // apply while the job runs with these attempts, so a runner that lost the job to another one
+    // after its timeout can't overwrite the outcome of the new run.
+    pub async fn claim(&self, kinds: &[&str], visibility_timeout: Duration) -> Result<Option<Job>> {
+        let row = sqlx::query(
+            "UPDATE job SET
+                status = 'running', attempts = attempts + 1,
+                locked_until = now() + make_interval(secs => $2), updated_at = now()
+            WHERE id = (
+                SELECT id FROM job
+                WHERE kind = ANY($1)
+                    AND ((status = 'queued' AND run_at <= now())
+                        OR (status = 'running' AND locked_until <= now()))
+                ORDER BY priority DESC, run_at, id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, kind, key, payload, attempts, max_attempts",
+        )
+        .bind(kinds)
+        .bind(visibility_timeout.as_secs_f64())
+        .fetch_optional(&self.pool)
+        .await
+        .context("failed to claim a job")?;
+
+        row.map(|row| {
+            Ok::<_, sqlx::Error>(Job {
+                id: row.try_get("id")?,
+                kind: row.try_get("kind")?,
+                key: row.try_get("key")?,
+                payload: row.try_get("payload")?,
+                attempts: row.try_get("attempts")?,
+                max_attempts: row.try_get("max_attempts")?,
+            })
+        })
+        .transpose()
+        .context("invalid job row")
+    }
+
+    // Pushes the visibility timeout of a running job back. Returns whether the job is still held,
+    // `false` once another runner took it over.
+    pub async fn heartbeat(
+        &self,
+        id: i64,
+        attempts: i32,
+        visibility_timeout: Duration,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE job SET locked_until = now() + make_interval(secs => $3)
+            WHERE id = $1 AND status = 'running' AND attempts = $2",
+        )
+        .bind(id)
+        .bind(attempts)
+        .bind(visibility_timeout.as_secs_f64())
+        .execute(&self.pool)
+        .await
+        .context("failed to extend the job lock")?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn complete(&self, id: i64, attempts: i32) -> Result<()> {
+        let result = sqlx::query(
+            "UPDATE job SET status = 'done', locked_until = NULL, last_error = NULL,
+                updated_at = now()
+            WHERE id = $1 AND status = 'running' AND attempts = $2",
+        )
+        .bind(id)
+        .bind(attempts)
+        .execute(&self.pool)
+        .await
+        .context("failed to complete the job")?;
+        ensure_held(id, result.rows_affected())
+    }
+
+    // Queues the job again after `backoff`, or fails it for good when `permanent` or out of
+    // attempts. Returns whether it failed for good.
+    pub async fn fail(
+        &self,
+        id: i64,
+        attempts: i32,
+        error: &str,
+        permanent: bool,
+        backoff: Duration,
+    ) -> Result<bool> {
+        let status: Option<String> = sqlx::query_scalar(
+            "UPDATE job SET
+                status = CASE WHEN $4 OR attempts >= max_attempts THEN 'failed' ELSE 'queued' END,
+                run_at = now() + make_interval(secs => $5), locked_until = NULL,
+                last_error = $3, updated_at = now()
+            WHERE id = $1 AND status = 'running' AND attempts = $2
+            RETURNING status",
+        )
+        .bind(id)
+        .bind(attempts)
+        .bind(error)
+        .bind(permanent)
+        .bind(backoff.as_secs_f64())
+        .fetch_optional(&self.pool)
+        .await
+        .context("failed to record the job failure")?;
+        let Some(status) = status else {
+            bail!("job {id} was taken over by another runner");
+        };
+        Ok(status == "failed")
+    }
+
+    // Hands a job that was interrupted back to the queue, the attempt doesn't count
+    pub async fn release(&self, id: i64, attempts: i32) -> Result<()> {
+        let result = sqlx::query(
+            "UPDATE job SET status = 'queued', attempts = attempts - 1, locked_until = NULL,
+                updated_at = now()
+            WHERE id = $1 AND status = 'running' AND attempts = $2",
+        )
+        .bind(id)
+        .bind(attempts)
+        .execute(&self.pool)
+        .await
+        .context("failed to release the job")?;
+        ensure_held(id, result.rows_affected())
+    }
+
+    // Number of the jobs of a kind with a status, `queued`, `running`, `done` or `failed`
+    pub async fn count(&self, kind: &str, status: &str) -> Result<i64> {
+        sqlx::query_scalar("SELECT COUNT(*) FROM job WHERE kind = $1 AND status = $2")
+            .bind(kind)
+            .bind(status)
+            .fetch_one(&self.pool)
+            .await
+            .with_context(|| format!("failed to count {status} {kind} jobs"))
+    }
+
+    // Most recent first
+    pub async fn failed(&self, kind: &str, limit: i64) -> Result<Vec<FailedJob>> {
+        let rows = sqlx::query(
+            "SELECT id, key, attempts, last_error, updated_at FROM job
+            WHERE kind = $1 AND status = 'failed'
+            ORDER BY updated_at DESC
+            LIMIT $2",
+        )
+        .bind(kind)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .with_context(|| format!("failed to list failed {kind} jobs"))?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok(FailedJob {
+                    id: row.try_get("id")?,
+                    key: row.try_get("key")?,
+                    attempts: row.try_get("attempts")?,
+                    error: row.try_get("last_error")?,
+                    failed_at: row.try_get("updated_at")?,
+                })
+            })
+            .collect::<Result<_, sqlx::Error>>()
+            .context("invalid job row")
+    }
+
+    // Queues every failed job of a kind again with fresh attempts, returns how many
+    pub async fn requeue_failed(&self, kind: &str) -> Result<u64> {
+        let result = sqlx::query(
+            "UPDATE job SET status = 'queued', attempts = 0, run_at = now(), last_error = NULL,
+                updated_at = now()
+            WHERE kind = $1 AND status = 'failed'",
+        )
+        .bind(kind)
+        .execute(&self.pool)
+        .await
+        .with_context(|| format!("failed to requeue failed {kind} jobs"))?;
+        Ok(result.rows_affected())
+    }
+}
+
+// The job was claimed again after the visibility timeout, the outcome belongs to the new run
+fn ensure_held(id: i64, rows_affected: u64) -> Result<()> {
+    if rows_affected == 0 {
+        bail!("job {id} was taken over by another runner");
+    }
+    Ok(())
+}
+
+// Run against a fresh database with the migration of the worker:
+// DATABASE_URL=postgres://... cargo test -p xlib -- --ignored
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KIND: &str = "test";
+    const TIMEOUT: Duration = Duration::from_mins(1);
+
+    async fn claim(queue: &JobQueue) -> Job {
+        queue.claim(&[KIND], TIMEOUT).await.unwrap().unwrap()
+    }
+
+    #[sqlx::test(migrations = "../services/img-to-vec-worker/migrations")]
+    #[ignore = "needs a Postgres server"]
+    async fn failed_job_is_claimed_again_then_completed(pool: Pool<Postgres>) {
+        let queue = JobQueue::new(pool);
+        let id = queue
+            .enqueue(NewJob::new(KIND).key("a").max_attempts(2))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let job = claim(&queue).await;
+        assert_eq!((job.id, job.attempts), (id, 1));
+        // running, nothing else to claim
+        assert!(queue.claim(&[KIND], TIMEOUT).await.unwrap().is_none());
+        let failed = queue
+            .fail(id, job.attempts, "boom", false, Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(!failed);
+
+        let job = claim(&queue).await;
+        assert_eq!((job.id, job.attempts), (id, 2));
+        queue.complete(id, job.attempts).await.unwrap();
+        assert_eq!(queue.count(KIND, "done").await.unwrap(), 1);
+        assert!(queue.claim(&[KIND], TIMEOUT).await.unwrap().is_none());
+    }
+
+    #[sqlx::test(migrations = "../services/img-to-vec-worker/migrations")]
+    #[ignore = "needs a Postgres server"]
+    async fn job_out_of_attempts_is_failed(pool: Pool<Postgres>) {
+        let queue = JobQueue::new(pool);
+        queue
+            .enqueue(NewJob::new(KIND).max_attempts(1))
+            .await
+            .unwrap();
+
+        let job = claim(&queue).await;
+        let failed = queue
+            .fail(job.id, job.attempts, "boom", false, Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(failed);
+        let dead = queue.failed(KIND, 10).await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].error.as_deref(), Some("boom"));
+    }
+
+    #[sqlx::test(migrations = "../services/img-to-vec-worker/migrations")]
+    #[ignore = "needs a Postgres server"]
+    async fn runner_that_lost_the_job_is_fenced_out(pool: Pool<Postgres>) {
+        let queue = JobQueue::new(pool);
+        queue.enqueue(NewJob::new(KIND)).await.unwrap();
+
+        let stale = queue.claim(&[KIND], Duration::ZERO).await.unwrap().unwrap();
+        // the visibility timeout expired, another runner takes the job over
+        let job = claim(&queue).await;
+        assert_eq!(job.attempts, stale.attempts + 1);
+
+        assert!(!queue
+            .heartbeat(stale.id, stale.attempts, TIMEOUT)
+            .await
+            .unwrap());
+        assert!(queue.complete(stale.id, stale.attempts).await.is_err());
+        assert!(queue
+            .fail(stale.id, stale.attempts, "boom", true, Duration::ZERO)
+            .await
+            .is_err());
+        assert!(queue.release(stale.id, stale.attempts).await.is_err());
+
+        queue.complete(job.id, job.attempts).await.unwrap();
+        assert_eq!(queue.count(KIND, "done").await.unwrap(), 1);
+    }
+}
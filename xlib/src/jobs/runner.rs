@@ -0,0 +1,378 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use cron::Schedule;
+use tokio::sync::Notify;
+use tracing::{info, warn, Instrument};
+
+use super::{Job, JobError, JobQueue};
+
+// Longest wait before a failed job is retried
+const MAX_RETRY_BACKOFF_SECS: u64 = 60 * 60;
+
+pub struct RunnerConfig {
+    // How often the queue is polled while idle, enqueuing through a `RunnerHandle` wakes the
+    // runner up right away
+    pub poll_interval: Duration,
+    // A running job is taken again by another runner once it isn't heard of for that long, the
+    // runner extends it at half of it while the job runs
+    pub visibility_timeout: Duration,
+    // First retry, doubled at every attempt
+    pub retry_backoff: Duration,
+    // How long the job being run is given to finish on shutdown, it is handed back to the queue
+    // after it
+    pub drain_timeout: Duration,
+}
+
+type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Result<(), JobError>> + 'a>>;
+type Handler<'a> = Box<dyn Fn(Job) -> HandlerFuture<'a> + 'a>;
+
+// Runs the jobs of the kinds it handles one at a time. Run as many runners as needed, in one
+// or several processes, they share the queue.
+pub struct Runner<'a> {
+    queue: JobQueue,
+    config: RunnerConfig,
+    handlers: HashMap<&'static str, Handler<'a>>,
+    schedules: Vec<(&'static str, Schedule)>,
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    paused: AtomicBool,
+    current: Mutex<Option<RunningJob>>,
+    wake: Notify,
+}
+
+#[derive(Debug, Clone)]
+pub struct RunningJob {
+    pub id: i64,
+    pub kind: String,
+    pub key: Option<String>,
+}
+
+// Steers a runner from elsewhere, e.g. a control API. Made before the runner, so that the
+// state its handlers borrow can hold it.
+#[derive(Clone, Default)]
+pub struct RunnerHandle(Arc<Shared>);
+
+impl RunnerHandle {
+    // The job being run is finished first
+    pub fn pause(&self) {
+        self.0.paused.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.0.paused.store(false, Ordering::Relaxed);
+        self.wake();
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn current(&self) -> Option<RunningJob> {
+        self.0.current.lock().unwrap().clone()
+    }
+
+    // Polls the queue now, after a job was enqueued
+    pub fn wake(&self) {
+        self.0.wake.notify_one();
+    }
+}
+
+enum Outcome {
+    Continue,
+    Shutdown,
+}
+
+impl<'a> Runner<'a> {
+    // Panics when the visibility timeout is too short to heartbeat at half of it
+    pub fn new(queue: JobQueue, config: RunnerConfig, handle: &RunnerHandle) -> Self {
+        assert!(
+            !(config.visibility_timeout / 2).is_zero(),
+            "the visibility timeout must be positive"
+        );
+        Self {
+            queue,
+            config,
+            handlers: HashMap::new(),
+            schedules: Vec::new(),
+            shared: handle.0.clone(),
+        }
+    }
+
+    #[must_use]
+    pub fn on<F, Fut>(mut self, kind: &'static str, handler: F) -> Self
+    where
+        F: Fn(Job) -> Fut + 'a,
+        Fut: Future<Output = Result<(), JobError>> + 'a,
+    {
+        self.handlers
+            .insert(kind, Box::new(move |job| Box::pin(handler(job))));
+        self
+    }
+
+    // Runs the job of `kind` at every fire time of `schedule`. A fire time is skipped while the
+    // previous run is queued or running, so the runs never overlap.
+    #[must_use]
+    pub fn schedule(mut self, kind: &'static str, schedule: Schedule) -> Self {
+        self.schedules.push((kind, schedule));
+        self
+    }
+
+    // Until `shutdown` completes
+    pub async fn run(self, shutdown: impl Future<Output = ()>) {
+        tokio::pin!(shutdown);
+        let kinds: Vec<&str> = self.handlers.keys().copied().collect();
+        for (kind, _) in &self.schedules {
+            self.arm_schedule(kind).await;
+        }
+
+        loop {
+            if !self.shared.paused.load(Ordering::Relaxed) {
+                match self
+                    .queue
+                    .claim(&kinds, self.config.visibility_timeout)
+                    .await
+                {
+                    Ok(Some(job)) => match self.run_job(job, &mut shutdown).await {
+                        Outcome::Continue => continue,
+                        Outcome::Shutdown => break,
+                    },
+                    Ok(None) => {}
+                    Err(e) => warn!("{:#}", e),
+                }
+            }
+
+            // idle: the queue is empty, the runner is paused or the database is unavailable
+            tokio::select! {
+                () = &mut shutdown => break,
+                () = tokio::time::sleep(self.config.poll_interval) => {}
+                () = self.shared.wake.notified() => {}
+            }
+        }
+        info!("Job runner stopped");
+    }
+
+    async fn run_job(
+        &self,
+        job: Job,
+        shutdown: &mut Pin<&mut impl Future<Output = ()>>,
+    ) -> Outcome {
+        let id = job.id;
+        let kind = job.kind.clone();
+        // its runner died while running it every time, e.g. the job crashes the process
+        if job.attempts > job.max_attempts {
+            warn!("Job {} ({}) ran out of attempts", id, kind);
+            self.record_failure(id, &kind, "ran out of attempts", true, job.attempts)
+                .await;
+            return Outcome::Continue;
+        }
+        let Some(handler) = self.handlers.get(kind.as_str()) else {
+            return Outcome::Continue;
+        };
+
+        *self.shared.current.lock().unwrap() = Some(RunningJob {
+            id,
+            kind: kind.clone(),
+            key: job.key.clone(),
+        });
+        let attempts = job.attempts;
+        let span =
+            tracing::info_span!("job", otel.name = %kind, job.id = id, job.attempts = attempts);
+        let start = Instant::now();
+        let mut shutting_down = false;
+        let mut taken_over = false;
+        // the handler is dropped at the end of the block, stopping a job that was taken over
+        let result = {
+            let running = handler(job).instrument(span);
+            tokio::pin!(running);
+
+            let mut heartbeat = tokio::time::interval(self.config.visibility_timeout / 2);
+            // the first tick is immediate, the job was just claimed
+            heartbeat.tick().await;
+            loop {
+                tokio::select! {
+                    result = &mut running => break Some(result),
+                    _ = heartbeat.tick() => {
+                        match self.queue.heartbeat(id, attempts, self.config.visibility_timeout).await {
+                            Ok(true) => {}
+                            // the other runner runs it again, running both would do the work twice
+                            Ok(false) => {
+                                taken_over = true;
+                                break None;
+                            }
+                            Err(e) => warn!("{:#}", e),
+                        }
+                    }
+                    () = &mut *shutdown => {
+                        info!("Received shutdown signal, finishing job {} ({}) within {:?}", id, kind, self.config.drain_timeout);
+                        shutting_down = true;
+                        break tokio::time::timeout(self.config.drain_timeout, &mut running).await.ok();
+                    }
+                }
+            }
+        };
+        *self.shared.current.lock().unwrap() = None;
+        metrics::histogram!("job_duration_seconds", "kind" => kind.clone())
+            .record(start.elapsed().as_secs_f64());
+
+        match result {
+            Some(Ok(())) => {
+                if let Err(e) = self.queue.complete(id, attempts).await {
+                    warn!("{:#}", e);
+                }
+                metrics::counter!("jobs_total", "kind" => kind.clone(), "outcome" => "done")
+                    .increment(1);
+            }
+            Some(Err(e)) => {
+                warn!("Job {} ({}) failed: {}", id, kind, e);
+                self.record_failure(id, &kind, &e.to_string(), e.permanent, attempts)
+                    .await;
+            }
+            None if taken_over => {
+                warn!(
+                    "Job {} ({}) was taken over by another runner, stopped it",
+                    id, kind
+                );
+                metrics::counter!("jobs_total", "kind" => kind.clone(), "outcome" => "taken_over")
+                    .increment(1);
+            }
+            None => {
+                warn!(
+                    "Job {} ({}) was not finished within {:?}, handing it back",
+                    id, kind, self.config.drain_timeout
+                );
+                if let Err(e) = self.queue.release(id, attempts).await {
+                    warn!("{:#}", e);
+                }
+                metrics::counter!("jobs_total", "kind" => kind.clone(), "outcome" => "released")
+                    .increment(1);
+            }
+        }
+
+        if self
+            .schedules
+            .iter()
+            .any(|(scheduled, _)| *scheduled == kind)
+        {
+            self.arm_schedule(&kind).await;
+        }
+        if shutting_down {
+            Outcome::Shutdown
+        } else {
+            Outcome::Continue
+        }
+    }
+
+    async fn record_failure(
+        &self,
+        id: i64,
+        kind: &str,
+        error: &str,
+        permanent: bool,
+        attempts: i32,
+    ) {
+        let outcome = match self
+            .queue
+            .fail(
+                id,
+                attempts,
+                error,
+                permanent,
+                retry_backoff(self.config.retry_backoff, attempts),
+            )
+            .await
+        {
+            Ok(true) => "failed",
+            Ok(false) => "retried",
+            Err(e) => {
+                warn!("{:#}", e);
+                return;
+            }
+        };
+        metrics::counter!("jobs_total", "kind" => kind.to_string(), "outcome" => outcome)
+            .increment(1);
+    }
+
+    // Queues the job of a scheduled kind at its next fire time
+    async fn arm_schedule(&self, kind: &str) {
+        let Some((_, schedule)) = self
+            .schedules
+            .iter()
+            .find(|(scheduled, _)| *scheduled == kind)
+        else {
+            return;
+        };
+        let Some(run_at) = schedule.upcoming(Utc).next() else {
+            return;
+        };
+        if let Err(e) = self.queue.arm_schedule(kind, run_at).await {
+            warn!("{:#}", e);
+        }
+    }
+}
+
+// `first` after the first attempt, doubled at every other one up to `MAX_RETRY_BACKOFF_SECS`
+fn retry_backoff(first: Duration, attempts: i32) -> Duration {
+    let exponent = u32::try_from(attempts.saturating_sub(1))
+        .unwrap_or(0)
+        .min(16);
+    first
+        .saturating_mul(1 << exponent)
+        .min(Duration::from_secs(MAX_RETRY_BACKOFF_SECS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_backoff_doubles_at_every_attempt() {
+        let first = Duration::from_secs(10);
+        assert_eq!(retry_backoff(first, 1), Duration::from_secs(10));
+        assert_eq!(retry_backoff(first, 2), Duration::from_secs(20));
+        assert_eq!(retry_backoff(first, 4), Duration::from_secs(80));
+    }
+
+    #[test]
+    fn retry_backoff_is_capped() {
+        let first = Duration::from_secs(10);
+        let max = Duration::from_secs(MAX_RETRY_BACKOFF_SECS);
+        assert_eq!(retry_backoff(first, 10), max);
+        assert_eq!(retry_backoff(first, i32::MAX), max);
+        assert_eq!(retry_backoff(Duration::MAX, 1), max);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "the visibility timeout must be positive")]
+    async fn zero_visibility_timeout_is_rejected() {
+        let pool = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/jobs")
+            .unwrap();
+        let config = RunnerConfig {
+            poll_interval: Duration::from_secs(1),
+            visibility_timeout: Duration::ZERO,
+            retry_backoff: Duration::from_secs(10),
+            drain_timeout: Duration::from_secs(10),
+        };
+        let _ = Runner::new(JobQueue::new(pool), config, &RunnerHandle::default());
+    }
+
+    #[test]
+    fn retry_backoff_without_attempts_is_the_first() {
+        let first = Duration::from_secs(10);
+        assert_eq!(retry_backoff(first, 0), first);
+        assert_eq!(retry_backoff(first, -1), first);
+    }
+}
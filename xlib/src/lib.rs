@@ -1,2 +1,4 @@
-pub mod client;
 pub mod app;
+pub mod client;
+pub mod collection;
+pub mod jobs;